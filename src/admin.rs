@@ -0,0 +1,238 @@
+use crate::{util, Result};
+use anyhow::{anyhow, Context};
+use log::{debug, error, info, warn};
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::SocketAddr,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+/// Admin socket of the server listening on `port` in the temporary directory,
+/// used unless `--admin-socket` is given.
+pub fn default_socket_path(port: u16) -> PathBuf {
+    env::temp_dir().join(format!("netspeed-admin-{}.sock", port))
+}
+
+/// What the admin socket asks of a running server.
+pub trait Admin: Send + Sync + 'static {
+    fn status(&self) -> Status;
+
+    /// End the sessions whose id, peer address or peer IP is `target`, returns their ids.
+    fn cancel(&self, target: &str) -> Result<Vec<u64>>;
+}
+
+/// Snapshot of a running server, shown by `netspeed server status`.
+#[derive(Debug, Clone, Default)]
+pub struct Status {
+    pub uptime: Duration,
+    /// Sessions served since start, including the active ones.
+    pub sessions: u64,
+    /// Payload bytes sent and received since start.
+    pub sent: u64,
+    pub received: u64,
+    pub workers: Vec<WorkerStatus>,
+}
+
+/// One active session.
+#[derive(Debug, Clone)]
+pub struct WorkerStatus {
+    /// Session id, unique for the lifetime of the server.
+    pub id: u64,
+    pub peer: SocketAddr,
+    /// `Downstream` or `Upstream` while a test is running.
+    pub direction: Option<String>,
+    /// Time since the session started.
+    pub elapsed: Duration,
+    /// Bytes moved and time spent by the running test.
+    pub test_bytes: u64,
+    pub test_elapsed: Duration,
+    /// Current throughput of the running test, measured over a short window.
+    pub bps: f64,
+}
+
+impl Status {
+    /// Line based wire format of the admin socket.
+    fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        writeln!(writer, "uptime {}", self.uptime.as_secs_f64())?;
+        writeln!(
+            writer,
+            "totals {} {} {}",
+            self.sessions, self.sent, self.received
+        )?;
+        for worker in &self.workers {
+            writeln!(
+                writer,
+                "worker {} {} {} {} {} {} {}",
+                worker.id,
+                worker.peer,
+                worker.direction.as_deref().unwrap_or("-"),
+                worker.elapsed.as_secs_f64(),
+                worker.test_bytes,
+                worker.test_elapsed.as_secs_f64(),
+                worker.bps
+            )?;
+        }
+        Ok(())
+    }
+
+    fn read<R: BufRead>(reader: R) -> Result<Self> {
+        let mut status = Status::default();
+        for line in reader.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields.as_slice() {
+                ["uptime", uptime] => status.uptime = Duration::from_secs_f64(uptime.parse()?),
+                ["totals", sessions, sent, received] => {
+                    status.sessions = sessions.parse()?;
+                    status.sent = sent.parse()?;
+                    status.received = received.parse()?;
+                }
+                ["worker", id, peer, direction, elapsed, test_bytes, test_elapsed, bps] => {
+                    status.workers.push(WorkerStatus {
+                        id: id.parse()?,
+                        peer: peer.parse()?,
+                        direction: Some(direction.to_string()).filter(|d| d != "-"),
+                        elapsed: Duration::from_secs_f64(elapsed.parse()?),
+                        test_bytes: test_bytes.parse()?,
+                        test_elapsed: Duration::from_secs_f64(test_elapsed.parse()?),
+                        bps: bps.parse()?,
+                    })
+                }
+                ["error", ..] => return Err(anyhow!("{}", &line["error ".len()..])),
+                _ => return Err(anyhow!("Unexpected status line {:?}", line)),
+            }
+        }
+        Ok(status)
+    }
+
+    pub fn print<W: Write>(&self, mut writer: W) -> Result<()> {
+        writeln!(writer, "    Uptime: {:.0} s", self.uptime.as_secs_f64())?;
+        writeln!(
+            writer,
+            "  Sessions: {} ({} active)",
+            self.sessions,
+            self.workers.len()
+        )?;
+        writeln!(writer, "      Sent: {}", util::format_bytes(self.sent))?;
+        writeln!(writer, "  Received: {}", util::format_bytes(self.received))?;
+        if self.workers.is_empty() {
+            return Ok(());
+        }
+        writeln!(writer)?;
+        writeln!(
+            writer,
+            "{:>6}  {:<40} {:<10} {:>9} {:>14}",
+            "ID", "Peer", "Direction", "Elapsed", "Throughput"
+        )?;
+        for worker in &self.workers {
+            let throughput = match worker.direction {
+                Some(_) => util::format_bps(worker.bps),
+                None => "-".to_owned(),
+            };
+            writeln!(
+                writer,
+                "{:>6}  {:<40} {:<10} {:>7.1} s {:>14}",
+                worker.id,
+                worker.peer.to_string(),
+                worker.direction.as_deref().unwrap_or("idle"),
+                worker.elapsed.as_secs_f64(),
+                throughput
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Answer admin requests on the Unix socket at `path` in the background.
+pub fn serve(path: &Path, admin: Arc<dyn Admin>) -> Result<()> {
+    if path.exists() {
+        // a leftover of a server that did not shut down cleanly.
+        if UnixStream::connect(path).is_ok() {
+            return Err(anyhow!("Admin socket {} is in use", path.display()));
+        }
+        fs::remove_file(path)
+            .with_context(|| format!("Remove stale admin socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Bind admin socket {}", path.display()))?;
+    info!("Admin socket on {}", path.display());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(err) = handle(stream, admin.as_ref()) {
+                        warn!("Admin request: {:#}", err);
+                    }
+                }
+                Err(err) => {
+                    error!("Accept admin connection: {}", err);
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle(stream: UnixStream, admin: &dyn Admin) -> Result<()> {
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+    let request = request.trim_end();
+    debug!("Admin request {:?}", request);
+    match request.split_once(' ') {
+        None if request == "status" => admin.status().write(&stream),
+        Some(("cancel", target)) => {
+            match admin.cancel(target) {
+                Ok(ids) => {
+                    for id in ids {
+                        writeln!(&stream, "cancelled {}", id)?;
+                    }
+                }
+                Err(err) => writeln!(&stream, "error {:#}", err)?,
+            }
+            Ok(())
+        }
+        _ => {
+            writeln!(&stream, "error Unknown request {:?}", request)?;
+            Ok(())
+        }
+    }
+}
+
+/// Ask the server behind the admin socket at `path` for its status.
+pub fn status(path: &Path) -> Result<Status> {
+    let stream = request(path, "status")?;
+    Status::read(BufReader::new(stream))
+}
+
+/// Cancel the sessions matching `target` on the server behind the admin socket at `path`,
+/// returns the ids of the cancelled sessions.
+pub fn cancel(path: &Path, target: &str) -> Result<Vec<u64>> {
+    let stream = request(path, &format!("cancel {}", target))?;
+    let mut ids = Vec::new();
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        match line.split_once(' ') {
+            Some(("cancelled", id)) => ids.push(id.parse()?),
+            Some(("error", err)) => return Err(anyhow!("{}", err)),
+            _ => return Err(anyhow!("Unexpected cancel answer {:?}", line)),
+        }
+    }
+    Ok(ids)
+}
+
+fn request(path: &Path, request: &str) -> Result<impl Read> {
+    let mut stream = UnixStream::connect(path).with_context(|| {
+        format!(
+            "Connect admin socket {}, is the server running?",
+            path.display()
+        )
+    })?;
+    writeln!(stream, "{}", request)?;
+    Ok(stream)
+}
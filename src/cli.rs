@@ -1,5 +1,6 @@
+use crate::{command::MAX_BLOCK_SIZE, util};
 use clap::{App, AppSettings, Arg, ArgMatches};
-use std::env;
+use std::{env, net::IpAddr};
 
 pub struct ArgParser {}
 
@@ -21,15 +22,141 @@ impl ArgParser {
                     .global(true)
                     .help("Logging verbose"),
             )
+            .arg(
+                Arg::with_name("units")
+                    .long("units")
+                    .global(true)
+                    .help(
+                        "Units of rates: si (Mbps), binary (Mibps), bytes (MB/s), \
+                         binary-bytes (MiB/s) or a fixed unit such as mbit, gbit, mibit, mbyte, mibyte",
+                    )
+                    .takes_value(true)
+                    .validator(|s| s.parse::<util::Units>().map(|_| ()))
+                    .default_value("si")
+                    .value_name("UNITS"),
+            )
+            .arg(
+                Arg::with_name("config")
+                    .long("config")
+                    .global(true)
+                    .help(
+                        "Config file with defaults for the flags \
+                         (default: ~/.config/netspeed/config.toml, /etc/netspeed/server.toml for server)",
+                    )
+                    .takes_value(true)
+                    .value_name("PATH"),
+            )
             .arg(
                 Arg::with_name("address")
                     .long("addr")
                     .alias("address")
                     .short("a")
-                    .help("Remote server address")
+                    .help(
+                        "Remote server address, `auto` uses the servers found on the local network. \
+                         Repeat to test against the server with the lowest latency",
+                    )
                     .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
                     .default_value("netspeed.ymgyt.io:5555"),
             )
+            .arg(
+                Arg::with_name("server-list")
+                    .long("server-list")
+                    .help("File with one server address per line to choose from by latency")
+                    .takes_value(true)
+                    .value_name("PATH"),
+            )
+            .arg(
+                Arg::with_name("accept")
+                    .long("accept")
+                    .help(
+                        "Reverse connection: listen on this address and wait for \
+                         a server started with --connect instead of dialing --addr",
+                    )
+                    .takes_value(true)
+                    .value_name("LISTEN_ADDR"),
+            )
+            .arg(
+                Arg::with_name("ipv4")
+                    .long("ipv4")
+                    .short("4")
+                    .conflicts_with("ipv6")
+                    .help("Connect to remote server over IPv4 only"),
+            )
+            .arg(
+                Arg::with_name("ipv6")
+                    .long("ipv6")
+                    .short("6")
+                    .help("Connect to remote server over IPv6 only"),
+            )
+            .arg(
+                Arg::with_name("bind")
+                    .long("bind")
+                    .help("Local address to connect from")
+                    .takes_value(true)
+                    .validator(|s| {
+                        s.parse::<IpAddr>()
+                            .map(|_| ())
+                            .map_err(|err| format!("{}", err))
+                    })
+                    .value_name("IP"),
+            )
+            .arg(
+                Arg::with_name("interface")
+                    .long("interface")
+                    .help("Network interface to send traffic through (Linux only)")
+                    .takes_value(true)
+                    .value_name("NAME"),
+            )
+            .arg(
+                Arg::with_name("send-buffer")
+                    .long("send-buffer")
+                    .alias("sndbuf")
+                    .help("Socket send buffer size (SO_SNDBUF), applied on both ends")
+                    .takes_value(true)
+                    .validator(validate_u32)
+                    .value_name("BYTES"),
+            )
+            .arg(
+                Arg::with_name("recv-buffer")
+                    .long("recv-buffer")
+                    .alias("rcvbuf")
+                    .help(
+                        "Socket receive buffer size (SO_RCVBUF), applied on both ends. \
+                         The server sets it after accepting the data connection, \
+                         too late to change the window scale of its side",
+                    )
+                    .takes_value(true)
+                    .validator(validate_u32)
+                    .value_name("BYTES"),
+            )
+            .arg(
+                Arg::with_name("no-delay")
+                    .long("no-delay")
+                    .help("Disable Nagle's algorithm (TCP_NODELAY) on both ends"),
+            )
+            .arg(
+                Arg::with_name("delay")
+                    .long("delay")
+                    .conflicts_with("no-delay")
+                    .help("Keep Nagle's algorithm, overrides `no_delay` in the config"),
+            )
+            .arg(
+                Arg::with_name("mss")
+                    .long("mss")
+                    .help("TCP maximum segment size, applied on both ends")
+                    .takes_value(true)
+                    .validator(validate_u32)
+                    .value_name("BYTES"),
+            )
+            .arg(
+                Arg::with_name("congestion")
+                    .long("congestion")
+                    .help("TCP congestion control algorithm (Linux only). e.g. cubic, bbr, reno")
+                    .takes_value(true)
+                    .value_name("ALGORITHM"),
+            )
             .arg(
                 Arg::with_name("duration")
                     .long("duration")
@@ -37,15 +164,115 @@ impl ArgParser {
                     .help("Speed test duration seconds(max: 10)")
                     .takes_value(true)
                     .default_value("3")
+                    .validator(validate_duration)
+                    .value_name("SECONDS"),
+            )
+            .arg(
+                Arg::with_name("omit")
+                    .long("omit")
+                    .help("Exclude the first seconds of each direction from the result (max: 10)")
+                    .takes_value(true)
+                    .validator(validate_omit)
+                    .value_name("SECONDS"),
+            )
+            .arg(
+                Arg::with_name("block-size")
+                    .long("block-size")
+                    .help("Payload bytes per data frame (max: 1 MiB)")
+                    .takes_value(true)
+                    .validator(validate_block_size)
+                    .value_name("BYTES"),
+            )
+            .arg(
+                Arg::with_name("payload")
+                    .long("payload")
+                    .help("Content of the data stream")
+                    .takes_value(true)
+                    .possible_values(&["zeros", "random"])
+                    .default_value("zeros"),
+            )
+            .arg(
+                Arg::with_name("seed")
+                    .long("seed")
+                    .help("Seed of the random payload (default: chosen at random)")
+                    .takes_value(true)
                     .validator(|s| {
-                        let n = s.parse::<u8>().map_err(|err| format!("{}", err))?;
-                        if n > 10 {
-                            Err("Max duration exceeded (max: 10)".to_owned())
-                        } else {
-                            Ok(())
-                        }
+                        s.parse::<u64>()
+                            .map(|_| ())
+                            .map_err(|err| format!("{}", err))
                     })
-                    .value_name("SECONDS"),
+                    .value_name("NUMBER"),
+            )
+            .arg(
+                Arg::with_name("payload-file")
+                    .long("payload-file")
+                    .help("Send the contents of a file (up to 1 MiB) as payload")
+                    .takes_value(true)
+                    .value_name("PATH"),
+            )
+            .arg(
+                Arg::with_name("verify-payload")
+                    .long("verify-payload")
+                    .help(
+                        "Stamp blocks with sequence numbers and checksums, \
+                         the receiving side reports corrupted, missing and reordered blocks",
+                    ),
+            )
+            .arg(
+                Arg::with_name("zerocopy")
+                    .long("zerocopy")
+                    .help("Send with sendfile(2) on both ends to reduce CPU usage (Linux only)"),
+            )
+            .arg(
+                Arg::with_name("format")
+                    .long("format")
+                    .help("Output format, csv prints one row per direction")
+                    .takes_value(true)
+                    .possible_values(&["text", "csv"])
+                    .default_value("text"),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .long("output")
+                    .short("o")
+                    .help("Write the result to a file instead of stdout")
+                    .takes_value(true)
+                    .value_name("PATH"),
+            )
+            .arg(
+                Arg::with_name("append")
+                    .long("append")
+                    .requires("output")
+                    .help("Append to the --output file, the csv header is only written once"),
+            )
+            .arg(
+                Arg::with_name("no-progress")
+                    .long("no-progress")
+                    .help("Do not draw live progress while testing"),
+            )
+            .arg(
+                Arg::with_name("min-downstream")
+                    .long("min-downstream")
+                    .help("Fail unless downstream throughput reaches this rate (e.g. 100M)")
+                    .takes_value(true)
+                    .validator(|s| util::parse_bps(&s).map(|_| ()))
+                    .value_name("BPS"),
+            )
+            .arg(
+                Arg::with_name("min-upstream")
+                    .long("min-upstream")
+                    .help("Fail unless upstream throughput reaches this rate (e.g. 100M)")
+                    .takes_value(true)
+                    .validator(|s| util::parse_bps(&s).map(|_| ()))
+                    .value_name("BPS"),
+            )
+            .arg(
+                Arg::with_name("max-latency")
+                    .long("max-latency")
+                    .help("Fail if round trip latency exceeds this value (e.g. 50ms)")
+                    .takes_value(true)
+                    .validator(|s| util::parse_duration(&s).map(|_| ()))
+                    .value_name("DURATION"),
             )
             .subcommand(
                 App::new("server")
@@ -54,7 +281,18 @@ impl ArgParser {
                         Arg::with_name("run")
                             .index(1)
                             .required(true)
-                            .help("Running server"),
+                            .possible_values(&["run", "status", "cancel"])
+                            .help(
+                                "run: running server, status: show the sessions of a running server, \
+                                 cancel: end the sessions of a running server matching TARGET",
+                            ),
+                    )
+                    .arg(
+                        Arg::with_name("target")
+                            .index(2)
+                            .required_if("run", "cancel")
+                            .help("Session id, peer address or peer IP to cancel")
+                            .value_name("TARGET"),
                     )
                     .arg(
                         Arg::with_name("address")
@@ -65,6 +303,27 @@ impl ArgParser {
                             .takes_value(true)
                             .default_value("0.0.0.0:5555"),
                     )
+                    .arg(
+                        Arg::with_name("announce")
+                            .long("announce")
+                            .help("Answer `netspeed discover` on the local network (udp port 5555)"),
+                    )
+                    .arg(
+                        Arg::with_name("no-announce")
+                            .long("no-announce")
+                            .conflicts_with("announce")
+                            .help("Do not answer `netspeed discover`, overrides `announce` in the config"),
+                    )
+                    .arg(
+                        Arg::with_name("connect")
+                            .long("connect")
+                            .help(
+                                "Reverse connection: dial a client waiting with --accept, \
+                                 serve one session and exit",
+                            )
+                            .takes_value(true)
+                            .value_name("CLIENT_ADDR"),
+                    )
                     .arg(
                         Arg::with_name("max-threads")
                             .long("max-threads")
@@ -73,8 +332,141 @@ impl ArgParser {
                             .takes_value(true)
                             .default_value("100")
                             .value_name("NUMBER"),
+                    )
+                    .arg(
+                        Arg::with_name("admin-socket")
+                            .long("admin-socket")
+                            .help(
+                                "Unix socket answering `netspeed server status` \
+                                 (default: netspeed-admin-PORT.sock in the temporary directory)",
+                            )
+                            .takes_value(true)
+                            .value_name("PATH"),
+                    ),
+            )
+            .subcommand(
+                App::new("listen")
+                    .about("Accept tests from a peer and print the results seen by this side")
+                    .arg(
+                        Arg::with_name("address")
+                            .long("addr")
+                            .alias("address")
+                            .short("a")
+                            .help("Listening address")
+                            .takes_value(true)
+                            .default_value("0.0.0.0:5555"),
+                    )
+                    .arg(
+                        Arg::with_name("once")
+                            .long("once")
+                            .help("Exit after the first test session"),
+                    ),
+            )
+            .subcommand(
+                App::new("monitor")
+                    .about("Repeat the test on a schedule and record the results")
+                    .arg(
+                        Arg::with_name("every")
+                            .long("every")
+                            .help("Interval between tests (e.g. 30s, 5m, 1h)")
+                            .takes_value(true)
+                            .validator(|s| util::parse_duration_with_unit(&s).map(|_| ()))
+                            .default_value("5m")
+                            .value_name("DURATION"),
+                    )
+                    .arg(
+                        Arg::with_name("count")
+                            .long("count")
+                            .help("Stop after this many tests")
+                            .takes_value(true)
+                            .validator(|s| {
+                                s.parse::<u64>()
+                                    .map(|_| ())
+                                    .map_err(|err| format!("{}", err))
+                            })
+                            .value_name("NUMBER"),
+                    )
+                    .arg(
+                        Arg::with_name("tui")
+                            .long("tui")
+                            .help("Show a full-screen dashboard instead of a line per result"),
+                    )
+                    .arg(history_arg()),
+            )
+            .subcommand(
+                App::new("history")
+                    .about("Summarize recorded results")
+                    .arg(
+                        Arg::with_name("since")
+                            .long("since")
+                            .help("Only results of this recent period (e.g. 24h, 7d)")
+                            .takes_value(true)
+                            .validator(|s| util::parse_duration_with_unit(&s).map(|_| ()))
+                            .value_name("DURATION"),
+                    )
+                    .arg(
+                        Arg::with_name("server")
+                            .long("server")
+                            .help("Only results of this server")
+                            .takes_value(true)
+                            .value_name("ADDR"),
+                    )
+                    .arg(history_arg()),
+            )
+            .subcommand(
+                App::new("discover")
+                    .about("List servers announcing themselves on the local network")
+                    .arg(
+                        Arg::with_name("timeout")
+                            .long("timeout")
+                            .help("How long to wait for answers (e.g. 500ms, 2s)")
+                            .takes_value(true)
+                            .validator(|s| util::parse_duration(&s).map(|_| ()))
+                            .default_value("1s")
+                            .value_name("DURATION"),
                     ),
             )
             .get_matches_from(args)
     }
 }
+
+fn history_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("history")
+        .long("history")
+        .help("CSV file of recorded results (default: ~/.local/share/netspeed/history.csv)")
+        .takes_value(true)
+        .value_name("PATH")
+}
+
+pub fn validate_u32(s: String) -> Result<(), String> {
+    s.parse::<u32>()
+        .map(|_| ())
+        .map_err(|err| format!("{}", err))
+}
+
+pub fn validate_duration(s: String) -> Result<(), String> {
+    let n = s.parse::<u8>().map_err(|err| format!("{}", err))?;
+    if n > 10 {
+        Err("Max duration exceeded (max: 10)".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn validate_omit(s: String) -> Result<(), String> {
+    let n = s.parse::<u8>().map_err(|err| format!("{}", err))?;
+    if n > 10 {
+        Err("Max omit exceeded (max: 10)".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn validate_block_size(s: String) -> Result<(), String> {
+    let n = s.parse::<usize>().map_err(|err| format!("{}", err))?;
+    if n == 0 || n > MAX_BLOCK_SIZE {
+        Err(format!("Block size must be 1..={}", MAX_BLOCK_SIZE))
+    } else {
+        Ok(())
+    }
+}
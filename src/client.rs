@@ -1,89 +1,539 @@
 use crate::{
-    command::{Command, DeclineReason, Operator},
-    Result,
+    command::{Command, Operator, Receipt, SendMode, Transfer, PROTOCOL_VERSION},
+    cpu::CpuTimer,
+    history::Record,
+    net::{self, Connector, SocketOptions, TcpStats},
+    payload::Payload,
+    progress::{Gauge, Observer, Progress},
+    util, Result,
 };
 use anyhow::{anyhow, Context};
-use log::{debug, info};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use socket2::{SockRef, Socket};
 use std::{
     fmt,
+    fs::OpenOptions,
     io::{self, Write},
-    net::{TcpStream, ToSocketAddrs},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
     str::FromStr,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 #[derive(Default, Debug)]
 struct Throughput {
-    bytes: u64,
+    /// Requested test duration.
     duration: Duration,
+    /// Bytes and time as seen by the receiving side.
+    received: Receipt,
+    /// Statistics of the sending side's socket.
+    tcp_stats: Option<TcpStats>,
+    /// Fraction of one core used by this client.
+    cpu: Option<f64>,
+    /// Fraction of one core used by the server.
+    remote_cpu: Option<f64>,
 }
 
 #[derive(Default, Debug)]
 struct NetworkSpec {
     downstream: Throughput,
     upstream: Throughput,
+    latency: Duration,
+    remote: Option<SocketAddr>,
+    local: Option<SocketAddr>,
+    local_socket: Option<SocketOptions>,
+    remote_socket: Option<SocketOptions>,
+}
+
+/// Pass/fail criteria checked against the measured `NetworkSpec`.
+#[derive(Default, Debug)]
+struct Thresholds {
+    min_downstream: Option<f64>,
+    min_upstream: Option<f64>,
+    max_latency: Option<Duration>,
+}
+
+/// How `Client::run` prints the result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Text,
+    /// One row per direction under a stable header.
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Format::Text),
+            "csv" => Ok(Format::Csv),
+            _ => Err(anyhow!("Invalid format {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Text => write!(f, "text"),
+            Format::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+const CSV_HEADER: &str = "timestamp,server,direction,bytes,seconds,bps,latency";
+
+/// File the result is written to instead of stdout.
+#[derive(Debug)]
+struct Output {
+    path: String,
+    append: bool,
+}
+
+/// Outcome of probing one server in `Client::select`.
+#[derive(Debug)]
+struct Candidate {
+    addr: String,
+    /// Round trip latency, or why the server could not be used.
+    latency: std::result::Result<Duration, String>,
+    selected: bool,
+}
+
+/// How long a reverse connection client waits for the server to open a data connection.
+const DATA_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for the reason after the server dropped a data connection.
+const DECLINE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Stops a running test early, e.g. on Ctrl-C. The results measured so far are kept.
+#[derive(Default)]
+pub struct Abort {
+    requested: Arc<AtomicBool>,
+    /// Control connection of the running session and its write lock, `Abort` is sent over it.
+    control: Mutex<Option<(Socket, Arc<Mutex<()>>)>>,
+}
+
+impl Abort {
+    /// Ask the server to stop. Returns false if already requested.
+    pub fn request(&self) -> bool {
+        if self.requested.swap(true, Ordering::Relaxed) {
+            return false;
+        }
+        if let Some((control, write_lock)) = self.control.lock().unwrap().as_ref() {
+            Abort::send(control, write_lock);
+        }
+        true
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    fn watch(&self, control: Socket, write_lock: Arc<Mutex<()>>) {
+        let mut watched = self.control.lock().unwrap();
+        if self.is_requested() {
+            Abort::send(&control, &write_lock);
+        }
+        *watched = Some((control, write_lock));
+    }
+
+    /// Sent between the requests of the main thread, never inside one.
+    fn send(mut control: &Socket, write_lock: &Mutex<()>) {
+        let _guard = write_lock.lock().unwrap();
+        if let Err(err) = control.write_all(&[Command::Abort.into()]) {
+            warn!("Send abort: {}", err);
+        }
+    }
 }
 
 pub struct Client {
+    /// Control connection, each test runs over its own data connection.
     operator: Operator,
+    connector: Connector,
+    remote: SocketAddr,
+    /// Set in reverse connection mode, where the server dials data connections into it.
+    listener: Option<TcpListener>,
+    token: u64,
+    /// Session accepted and latency measured, done up front by `Client::select`.
+    ready: bool,
+    /// Servers considered by `Client::select`, reported with the result.
+    candidates: Vec<Candidate>,
     spec: NetworkSpec,
+    thresholds: Thresholds,
+    options: SocketOptions,
+    transfer: Transfer,
+    format: Format,
+    output: Option<Output>,
+    /// Told about the progress of running tests.
+    observer: Option<Arc<dyn Observer>>,
+    abort: Option<Arc<Abort>>,
 }
 
 impl Client {
     pub fn new(addr: impl ToSocketAddrs + fmt::Debug) -> Result<Self> {
+        Client::with_connector(addr, &Connector::new())
+    }
+
+    pub fn with_connector(
+        addr: impl ToSocketAddrs + fmt::Debug,
+        connector: &Connector,
+    ) -> Result<Self> {
         info!("Connecting to {:?}", addr);
+        let stream = connector.connect(&addr)?;
+        Client::from_stream(stream, connector, None)
+    }
+
+    /// Probe every server and keep the one accepting the session with the lowest latency.
+    pub fn select(addrs: &[String], connector: &Connector) -> Result<Self> {
+        let probed: Vec<Result<Client>> = thread::scope(|scope| {
+            let probes: Vec<_> = addrs
+                .iter()
+                .map(|addr| scope.spawn(move || Client::probe(addr, connector)))
+                .collect();
+            probes
+                .into_iter()
+                .map(|probe| {
+                    probe
+                        .join()
+                        .unwrap_or_else(|_| Err(anyhow!("Probe panicked")))
+                })
+                .collect()
+        });
+
+        let mut candidates = Vec::with_capacity(addrs.len());
+        let mut best: Option<(usize, Client)> = None;
+        for (addr, probe) in addrs.iter().zip(probed) {
+            let latency = match probe {
+                Ok(client) => {
+                    let latency = client.spec.latency;
+                    if best
+                        .as_ref()
+                        .is_none_or(|(_, best)| latency < best.spec.latency)
+                    {
+                        best = Some((candidates.len(), client));
+                    }
+                    Ok(latency)
+                }
+                Err(err) => {
+                    warn!("Skip {}: {:#}", addr, err);
+                    Err(format!("{:#}", err))
+                }
+            };
+            candidates.push(Candidate {
+                addr: addr.clone(),
+                latency,
+                selected: false,
+            });
+        }
+
+        let (selected, mut client) =
+            best.ok_or_else(|| anyhow!("No server available among {}", addrs.join(", ")))?;
+        info!(
+            "Selected {} latency: {}",
+            addrs[selected],
+            util::format_latency(client.spec.latency)
+        );
+        candidates[selected].selected = true;
+        client.candidates = candidates;
+        Ok(client)
+    }
+
+    fn probe(addr: &str, connector: &Connector) -> Result<Self> {
+        let mut client = Client::with_connector(addr, connector)?;
+        client.prepare()?;
+        Ok(client)
+    }
+
+    /// Reverse connection: listen on `addr` and wait for a server started with `--connect`.
+    /// Data connections are dialed by the server as well, the test itself is unchanged.
+    pub fn accept(addr: impl ToSocketAddrs + fmt::Debug, connector: &Connector) -> Result<Self> {
+        let listener = TcpListener::bind(&addr).with_context(|| format!("Listen on {:?}", addr))?;
+        info!("Waiting for server on {}", listener.local_addr()?);
+        let (stream, _) = listener.accept()?;
+        connector.options().apply(SockRef::from(&stream))?;
+        Client::from_stream(stream, connector, Some(listener))
+    }
+
+    fn from_stream(
+        stream: TcpStream,
+        connector: &Connector,
+        listener: Option<TcpListener>,
+    ) -> Result<Self> {
+        let remote = stream.peer_addr()?;
+        let local = stream.local_addr()?;
+        info!("Connected to {} from {}", remote, local);
+
+        let mut operator = Operator::new(stream);
+        operator.open_session()?;
 
         Ok(Self {
-            operator: Operator::new(
-                TcpStream::connect_timeout(
-                    &addr.to_socket_addrs().unwrap().next().unwrap(),
-                    Duration::from_secs(3),
-                )
-                .context(format!("Addr:{:?}", addr))?,
-            ),
-            spec: NetworkSpec::default(),
+            operator,
+            connector: connector.clone(),
+            remote,
+            listener,
+            token: 0,
+            ready: false,
+            candidates: Vec::new(),
+            spec: NetworkSpec {
+                remote: Some(remote),
+                local: Some(local),
+                ..Default::default()
+            },
+            thresholds: Thresholds::default(),
+            options: connector.options().clone(),
+            transfer: Transfer::default(),
+            format: Format::Text,
+            output: None,
+            observer: None,
+            abort: None,
         })
     }
 
     pub fn duration(mut self, duration: Option<&str>) -> Self {
-        let duration =
-            Duration::from_secs(u64::from_str(duration.unwrap_or("3").as_ref()).unwrap());
+        let duration = Duration::from_secs(u64::from_str(duration.unwrap_or("3")).unwrap());
         self.spec.downstream.duration = duration;
         self.spec.upstream.duration = duration;
         self
     }
 
+    /// Send with `sendfile(2)` on both ends to take payload copies off the CPU.
+    pub fn zerocopy(mut self, enabled: bool) -> Self {
+        self.transfer.mode = if enabled {
+            SendMode::ZeroCopy
+        } else {
+            SendMode::Copy
+        };
+        self
+    }
+
+    pub fn payload(mut self, payload: Payload) -> Self {
+        self.transfer.payload = payload;
+        self
+    }
+
+    /// Stamp blocks with sequence numbers and checksums the receiving side checks.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.transfer.verify = verify;
+        self
+    }
+
+    /// Seconds of warm-up excluded from the result.
+    pub fn omit(mut self, omit: Option<&str>) -> Self {
+        if let Some(omit) = omit {
+            self.transfer.omit = Duration::from_secs(u64::from_str(omit).unwrap());
+        }
+        self
+    }
+
+    /// Payload bytes per data frame.
+    pub fn block_size(mut self, block_size: Option<&str>) -> Self {
+        if let Some(block_size) = block_size {
+            self.transfer.block_size = u32::from_str(block_size).unwrap();
+        }
+        self
+    }
+
+    pub fn min_downstream(mut self, bps: Option<&str>) -> Self {
+        self.thresholds.min_downstream = bps.map(|bps| util::parse_bps(bps).unwrap());
+        self
+    }
+
+    pub fn min_upstream(mut self, bps: Option<&str>) -> Self {
+        self.thresholds.min_upstream = bps.map(|bps| util::parse_bps(bps).unwrap());
+        self
+    }
+
+    pub fn max_latency(mut self, latency: Option<&str>) -> Self {
+        self.thresholds.max_latency = latency.map(|latency| util::parse_duration(latency).unwrap());
+        self
+    }
+
+    pub fn format(mut self, format: Option<&str>) -> Self {
+        if let Some(format) = format {
+            self.format = Format::from_str(format).unwrap();
+        }
+        self
+    }
+
+    /// Show direction, elapsed time and current throughput on a gauge while testing.
+    pub fn progress(self, enabled: bool) -> Self {
+        if enabled {
+            self.observer(Arc::new(Gauge))
+        } else {
+            self
+        }
+    }
+
+    /// Report the progress of running tests to `observer`.
+    pub fn observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Stop the running test once `abort` is requested.
+    pub fn abort(mut self, abort: Arc<Abort>) -> Self {
+        self.abort = Some(abort);
+        self
+    }
+
+    fn aborted(&self) -> bool {
+        self.abort
+            .as_ref()
+            .is_some_and(|abort| abort.is_requested())
+    }
+
+    /// Write the result to `path`, after its current content if `append`.
+    pub fn output(mut self, path: Option<&str>, append: bool) -> Self {
+        self.output = path.map(|path| Output {
+            path: path.to_owned(),
+            append,
+        });
+        self
+    }
+
     pub fn run(mut self) -> Result<()> {
-        self.check_server_status()
-            .and_then(|_| self.ping_pon())
-            .and_then(|_| self.downstream())
-            .and_then(|_| self.upstream())
-            .and_then(|_| self.print_result(io::stdout()))
+        let timestamp = Utc::now();
+        self.test()?;
+        let (mut writer, empty) = self.open_output()?;
+        match self.format {
+            Format::Text => self.print_result(&mut writer)?,
+            Format::Csv => self.print_csv(&mut writer, timestamp, empty)?,
+        }
+        if self.aborted() {
+            return Err(anyhow!("Test aborted, the results are partial"));
+        }
+        self.check_thresholds()
+    }
+
+    /// Writer for the result and whether it has no previous content.
+    fn open_output(&self) -> Result<(Box<dyn Write>, bool)> {
+        match self.output.as_ref() {
+            Some(output) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .append(output.append)
+                    .truncate(!output.append)
+                    .open(&output.path)
+                    .with_context(|| format!("Open output {}", output.path))?;
+                let empty = file.metadata()?.len() == 0;
+                Ok((Box::new(file), empty))
+            }
+            None => Ok((Box::new(io::stdout()), true)),
+        }
+    }
+
+    /// Run the test without printing the result and return it as a history record.
+    pub fn measure(mut self) -> Result<Record> {
+        let timestamp = Utc::now();
+        self.test()?;
+        Ok(Record {
+            timestamp,
+            server: self.remote.to_string(),
+            downstream_bps: self.bps(&self.spec.downstream),
+            upstream_bps: self.bps(&self.spec.upstream),
+            latency: self.spec.latency,
+        })
+    }
+
+    fn test(&mut self) -> Result<()> {
+        if !self.ready {
+            self.prepare()?;
+        }
+        if let Some(abort) = self.abort.as_ref() {
+            abort.watch(
+                self.operator.socket().try_clone()?,
+                self.operator.write_lock(),
+            );
+        }
+        self.negotiate_socket_options()?;
+        // an aborted test keeps what was measured before.
+        if !self.aborted() {
+            self.downstream()?;
+        }
+        if !self.aborted() {
+            self.upstream()?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the server to accept the session and measure the latency.
+    fn prepare(&mut self) -> Result<()> {
+        self.check_server_status().and_then(|_| self.ping_pon())?;
+        self.ready = true;
+        Ok(())
     }
 
     fn check_server_status(&mut self) -> Result<()> {
         let cmd = self.operator.read()?;
         match cmd {
             Command::Ready => {
-                debug!("Receive server ready");
+                let version = self.operator.read_version().map_err(|err| {
+                    match err.downcast_ref::<io::Error>() {
+                        // servers before the version field send a bare `Ready` and then drop
+                        // the connection on the unexpected `Session`.
+                        Some(io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof => anyhow!(
+                            "Protocol version mismatch, server speaks version 1 and client version {}",
+                            PROTOCOL_VERSION
+                        ),
+                        _ => err,
+                    }
+                })?;
+                if version != PROTOCOL_VERSION {
+                    return Err(anyhow!(
+                        "Protocol version mismatch, server speaks version {} and client version {}",
+                        version,
+                        PROTOCOL_VERSION
+                    ));
+                }
+                self.token = self.operator.read_token()?;
+                debug!("Receive server ready. session: {:x}", self.token);
                 Ok(())
             }
-            Command::Decline => match self.operator.read_decline_reason()? {
-                DeclineReason::Unknown => Err(anyhow!("Server decline speed test :(")),
-                DeclineReason::MaxThreadsExceed(max_threads) => Err(anyhow!(
-                    "Server decline speed test. Cause: max threads exceeded({})",
-                    max_threads
-                )),
-            },
+            Command::Decline => {
+                let reason = self.operator.read_decline_reason()?;
+                Err(anyhow!("Server decline speed test. Cause: {}", reason))
+            }
             _ => Err(anyhow!("Unexpected command {:?}", cmd)),
         }
     }
 
     fn ping_pon(&mut self) -> Result<()> {
-        self.operator.ping_write_then_read().map(|r| {
-            debug!("Successfully ping to remote server");
-            r
-        })
+        let start = Instant::now();
+        self.operator.ping_write_then_read()?;
+        self.spec.latency = start.elapsed();
+        debug!(
+            "Successfully ping to remote server. latency: {}",
+            util::format_latency(self.spec.latency)
+        );
+        Ok(())
+    }
+
+    fn negotiate_socket_options(&mut self) -> Result<()> {
+        if self.options.is_empty() {
+            return Ok(());
+        }
+        self.operator.request_socket_options(&self.options)
+    }
+
+    /// Effective options of the data sockets on both ends, the server reports its side
+    /// after each data phase once options were requested.
+    fn read_socket_options(&mut self, data: &Operator) -> Result<()> {
+        if self.options.is_empty() {
+            return Ok(());
+        }
+        self.spec.local_socket = Some(SocketOptions::effective(data.socket()));
+        let remote = self.operator.read_socket_options_report()?;
+        debug!("Server socket options: {}", remote);
+        self.spec.remote_socket = Some(remote);
+        Ok(())
     }
 
     fn downstream(&mut self) -> Result<()> {
@@ -92,8 +542,21 @@ impl Client {
             self.spec.downstream.duration.as_secs()
         );
         self.operator
-            .request_downstream(self.spec.downstream.duration)?;
-        self.spec.downstream.bytes = self.operator.read_loop()?;
+            .request_downstream(&self.transfer(self.spec.downstream.duration))?;
+        let mut data = self.open_data()?;
+        let progress = self.start_progress("Downstream", self.spec.downstream.duration, &mut data);
+        let cpu = CpuTimer::start();
+        self.spec.downstream.received =
+            data.read_loop(&self.transfer(self.spec.downstream.duration))?;
+        self.spec.downstream.cpu = cpu.utilization();
+        drop(progress);
+        if self.aborted() {
+            // the server may not have ended the transfer, its statistics are not awaited.
+            return Ok(());
+        }
+        self.read_socket_options(&data)?;
+        self.spec.downstream.tcp_stats = self.operator.read_tcp_stats()?;
+        self.spec.downstream.remote_cpu = self.operator.read_cpu_usage()?;
         Ok(())
     }
 
@@ -103,12 +566,122 @@ impl Client {
             self.spec.upstream.duration.as_secs()
         );
         self.operator
-            .request_upstream(self.spec.upstream.duration)?;
-        self.spec.upstream.bytes = self.operator.write_loop(self.spec.upstream.duration)?;
+            .request_upstream(&self.transfer(self.spec.upstream.duration))?;
+        let mut data = self.open_data()?;
+        let progress = self.start_progress("Upstream", self.spec.upstream.duration, &mut data);
+        let before = data.tcp_stats();
+        let cpu = CpuTimer::start();
+        let write_bytes = match data.write_loop(&self.transfer(self.spec.upstream.duration)) {
+            Ok(write_bytes) => write_bytes,
+            Err(err) => return Err(self.declined().unwrap_or(err)),
+        };
+        drop(progress);
+        debug!("Write {}", util::format_bytes(write_bytes));
+        self.spec.upstream.cpu = cpu.utilization();
+        self.spec.upstream.tcp_stats = data.tcp_stats().map(|after| after.since(before.as_ref()));
+        self.read_socket_options(&data)?;
+        drop(data);
+        self.spec.upstream.received = self.operator.read_receipt()?;
         Ok(())
     }
 
+    /// Reason given by the server for ending the session, if it did.
+    /// The server closes the data connection first, so a failed upstream is checked here.
+    fn declined(&mut self) -> Option<anyhow::Error> {
+        self.operator
+            .socket()
+            .set_read_timeout(Some(DECLINE_TIMEOUT))
+            .ok()?;
+        match self.operator.read() {
+            Ok(Command::Decline) => self
+                .operator
+                .read_decline_reason()
+                .ok()
+                .map(|reason| anyhow!("Server ended the session: {}", reason)),
+            _ => None,
+        }
+    }
+
+    fn start_progress(
+        &self,
+        label: &'static str,
+        duration: Duration,
+        data: &mut Operator,
+    ) -> Option<Progress> {
+        let observer = Arc::clone(self.observer.as_ref()?);
+        let progress = Progress::start(label, self.transfer.omit + duration, observer);
+        data.track_progress(progress.counter());
+        Some(progress)
+    }
+
+    fn open_data(&self) -> Result<Operator> {
+        let stream = match self.listener.as_ref() {
+            Some(listener) => self.accept_data(listener)?,
+            None => self.connector.connect(self.remote)?,
+        };
+        let mut data = Operator::new(stream);
+        data.attach(self.token)?;
+        if let Some(abort) = self.abort.as_ref() {
+            data.stop_on(Arc::clone(&abort.requested));
+        }
+        Ok(data)
+    }
+
+    fn accept_data(&self, listener: &TcpListener) -> Result<TcpStream> {
+        let deadline = Instant::now() + DATA_CONNECTION_TIMEOUT;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let (stream, addr) =
+                net::accept_timeout(listener, timeout).context("Wait data connection")?;
+            if addr.ip() != self.remote.ip() {
+                warn!(
+                    "Ignore connection from {}, expecting {}",
+                    addr,
+                    self.remote.ip()
+                );
+                continue;
+            }
+            self.options.apply(SockRef::from(&stream))?;
+            return Ok(stream);
+        }
+    }
+
+    fn transfer(&self, duration: Duration) -> Transfer {
+        Transfer {
+            duration,
+            ..self.transfer.clone()
+        }
+    }
+
     fn print_result<W: Write>(&mut self, mut writer: W) -> Result<()> {
+        for candidate in self.candidates.iter() {
+            match candidate.latency.as_ref() {
+                Ok(latency) => writeln!(
+                    writer,
+                    " Candidate: {} {}{}",
+                    candidate.addr,
+                    util::format_latency(*latency),
+                    if candidate.selected {
+                        " (selected)"
+                    } else {
+                        ""
+                    }
+                )?,
+                Err(err) => writeln!(writer, " Candidate: {} {}", candidate.addr, err)?,
+            }
+        }
+        if let Some(remote) = self.spec.remote {
+            writeln!(writer, "    Remote: {}", remote)?;
+        }
+        if let Some(local) = self.spec.local {
+            writeln!(writer, "     Local: {}", local)?;
+        }
+        if let Some(options) = self.spec.local_socket.as_ref() {
+            writeln!(writer, "   Sockopt: {} (local)", options)?;
+        }
+        if let Some(options) = self.spec.remote_socket.as_ref() {
+            writeln!(writer, "   Sockopt: {} (remote)", options)?;
+        }
         writeln!(
             writer,
             "Downstream: {}",
@@ -119,11 +692,163 @@ impl Client {
             "  Upstream: {}",
             self.format_throughput(&self.spec.upstream)
         ))
-        .map_err(anyhow::Error::from)
+        .and(writeln!(
+            writer,
+            "   Latency: {}",
+            util::format_latency(self.spec.latency)
+        ))?;
+        if self.transfer.payload != Payload::Zeros {
+            writeln!(writer, "   Payload: {}", self.transfer.payload)?;
+        }
+        if self.transfer.verify {
+            writeln!(
+                writer,
+                " Integrity: down {} up {}",
+                self.format_integrity(&self.spec.downstream),
+                self.format_integrity(&self.spec.upstream)
+            )?;
+        }
+        if !self.transfer.omit.is_zero() {
+            writeln!(
+                writer,
+                "   Omitted: down {} up {} (first {} seconds)",
+                self.format_omitted(&self.spec.downstream),
+                self.format_omitted(&self.spec.upstream),
+                self.transfer.omit.as_secs()
+            )?;
+        }
+        if let Some(stats) = self.spec.downstream.tcp_stats.as_ref() {
+            writeln!(writer, "  Down TCP: {} (server)", stats)?;
+        }
+        if let Some(stats) = self.spec.upstream.tcp_stats.as_ref() {
+            writeln!(writer, "    Up TCP: {} (local)", stats)?;
+        }
+        if let (Some(down), Some(up)) = (self.spec.downstream.cpu, self.spec.upstream.cpu) {
+            writeln!(
+                writer,
+                "       CPU: down {:.1}% up {:.1}% (local)",
+                down * 100f64,
+                up * 100f64
+            )?;
+        }
+        if let Some(down) = self.spec.downstream.remote_cpu {
+            writeln!(writer, "       CPU: down {:.1}% (server)", down * 100f64)?;
+        }
+        Ok(())
+    }
+
+    fn print_csv<W: Write>(
+        &self,
+        mut writer: W,
+        timestamp: DateTime<Utc>,
+        header: bool,
+    ) -> Result<()> {
+        if header {
+            writeln!(writer, "{}", CSV_HEADER)?;
+        }
+        for (direction, throughput) in [
+            ("downstream", &self.spec.downstream),
+            ("upstream", &self.spec.upstream),
+        ] {
+            writeln!(
+                writer,
+                "{},{},{},{},{:.6},{:.0},{:.6}",
+                timestamp.to_rfc3339(),
+                self.remote,
+                direction,
+                throughput.received.bytes,
+                throughput.received.elapsed.as_secs_f64(),
+                self.bps(throughput),
+                self.spec.latency.as_secs_f64()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Failed assertions go to stderr, so they are seen even with `--output`
+    /// and csv rows stay parseable.
+    fn check_thresholds(&self) -> Result<()> {
+        let mut failures = Vec::new();
+        if let Some(min) = self.thresholds.min_downstream {
+            let actual = self.bps(&self.spec.downstream);
+            if actual < min {
+                failures.push(format!(
+                    "Downstream {} is below minimum {}",
+                    util::format_bps(actual),
+                    util::format_bps(min)
+                ));
+            }
+        }
+        if let Some(min) = self.thresholds.min_upstream {
+            let actual = self.bps(&self.spec.upstream);
+            if actual < min {
+                failures.push(format!(
+                    "Upstream {} is below minimum {}",
+                    util::format_bps(actual),
+                    util::format_bps(min)
+                ));
+            }
+        }
+        for (direction, throughput) in [
+            ("Downstream", &self.spec.downstream),
+            ("Upstream", &self.spec.upstream),
+        ] {
+            if let Some(integrity) = throughput.received.integrity.filter(|i| !i.is_ok()) {
+                failures.push(format!("{} payload integrity: {}", direction, integrity));
+            }
+        }
+        if let Some(max) = self.thresholds.max_latency {
+            if self.spec.latency > max {
+                failures.push(format!(
+                    "Latency {} exceeds maximum {}",
+                    util::format_latency(self.spec.latency),
+                    util::format_latency(max)
+                ));
+            }
+        }
+
+        for failure in failures.iter() {
+            eprintln!("Assertion failed: {}", failure);
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("{} threshold assertion(s) failed", failures.len()))
+        }
+    }
+
+    fn bps(&self, throughput: &Throughput) -> f64 {
+        let received = &throughput.received;
+        if received.elapsed.is_zero() {
+            0f64
+        } else {
+            util::to_bps(received.bytes, received.elapsed)
+        }
+    }
+
+    fn format_integrity(&self, throughput: &Throughput) -> String {
+        match throughput.received.integrity {
+            Some(integrity) => integrity.to_string(),
+            None => "not verified".to_owned(),
+        }
+    }
+
+    fn format_omitted(&self, throughput: &Throughput) -> String {
+        let received = &throughput.received;
+        if received.omitted_elapsed.is_zero() {
+            util::format_bps(0f64)
+        } else {
+            util::format_bps(util::to_bps(
+                received.omitted_bytes,
+                received.omitted_elapsed,
+            ))
+        }
     }
 
     fn format_throughput(&self, throughput: &Throughput) -> String {
-        use crate::util::*;
-        format_bps(to_bps(throughput.bytes, throughput.duration))
+        if self.aborted() && throughput.received == Receipt::default() {
+            return "aborted".to_owned();
+        }
+        util::format_bps(self.bps(throughput))
     }
 }
@@ -1,28 +1,169 @@
-use crate::Result;
+#[cfg(target_os = "linux")]
+use crate::net::SendFile;
+use crate::{
+    net::{SocketOptions, TcpStats},
+    payload::{BlockStamper, BlockVerifier, Cursor, Integrity, Payload, BLOCK_HEADER_SIZE},
+    Result,
+};
 use anyhow::anyhow;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use log::warn;
+use socket2::SockRef;
 use std::{
     convert::{From, TryFrom},
-    io::{Read, Write},
+    fmt,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
     net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{self, Duration},
 };
 
+/// How the sending side hands the payload to the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendMode {
+    /// `write(2)` from a user space buffer.
+    Copy,
+    /// `sendfile(2)` from an in-memory file (Linux only, falls back to `Copy`).
+    ZeroCopy,
+}
+
+impl From<SendMode> for u8 {
+    fn from(mode: SendMode) -> Self {
+        match mode {
+            SendMode::Copy => 0,
+            SendMode::ZeroCopy => 1,
+        }
+    }
+}
+
+impl TryFrom<u8> for SendMode {
+    type Error = anyhow::Error;
+    fn try_from(n: u8) -> Result<Self> {
+        match n {
+            0 => Ok(SendMode::Copy),
+            1 => Ok(SendMode::ZeroCopy),
+            _ => Err(anyhow!("Invalid number {} for send mode", n)),
+        }
+    }
+}
+
+/// Parameters of one direction of the measurement, sent along with the request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    pub duration: Duration,
+    /// Warm-up sent before `duration` and excluded from the result.
+    pub omit: Duration,
+    pub mode: SendMode,
+    /// Payload bytes per data frame.
+    pub block_size: u32,
+    pub payload: Payload,
+    /// Blocks carry a sequence number and checksum the receiver checks.
+    pub verify: bool,
+}
+
+impl Default for Transfer {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_secs(3),
+            omit: Duration::from_secs(0),
+            mode: SendMode::Copy,
+            block_size: crate::BUFFER_SIZE as u32,
+            payload: Payload::Zeros,
+            verify: false,
+        }
+    }
+}
+
+/// What the receiving side observed during the data phase.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Receipt {
+    pub bytes: u64,
+    /// From the end of the omitted warm-up (or the first received byte) until the end marker.
+    pub elapsed: Duration,
+    pub omitted_bytes: u64,
+    pub omitted_elapsed: Duration,
+    /// Block level verification result, `None` unless verified.
+    pub integrity: Option<Integrity>,
+}
+
+impl Receipt {
+    /// Result of a receiver that started at `first` and began measuring at `measure_from`.
+    fn measured(
+        read_bytes: u64,
+        now: time::Instant,
+        first: time::Instant,
+        measure_from: Option<time::Instant>,
+        omitted: (u64, Duration),
+        integrity: Option<Integrity>,
+    ) -> Self {
+        match measure_from {
+            Some(from) => Receipt {
+                bytes: read_bytes,
+                elapsed: now - from,
+                omitted_bytes: omitted.0,
+                omitted_elapsed: omitted.1,
+                integrity,
+            },
+            // the transfer ended within the warm-up.
+            None => Receipt {
+                omitted_bytes: read_bytes,
+                omitted_elapsed: now - first,
+                integrity,
+                ..Receipt::default()
+            },
+        }
+    }
+}
+
 pub enum DeclineReason {
     Unknown,
     MaxThreadsExceed(u32),
+    /// The session was cancelled by the server administrator.
+    Cancelled,
+    /// The client speaks another protocol version, the server's is given.
+    VersionMismatch(u16),
+}
+
+impl fmt::Display for DeclineReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeclineReason::Unknown => write!(f, "unknown reason"),
+            DeclineReason::MaxThreadsExceed(max_threads) => {
+                write!(f, "max threads exceeded({})", max_threads)
+            }
+            DeclineReason::Cancelled => write!(f, "cancelled by the server administrator"),
+            DeclineReason::VersionMismatch(version) => write!(
+                f,
+                "protocol version mismatch, server speaks version {} and client version {}",
+                version, PROTOCOL_VERSION
+            ),
+        }
+    }
 }
 
+/// Exchanged with `Session` and `Ready` so mismatching peers fail with a clear error.
+/// The protocol before the version field was added counts as version 1.
+pub const PROTOCOL_VERSION: u16 = 2;
+
 #[repr(u8)]
 #[derive(Debug, Eq, PartialEq)]
 pub enum Command {
     Ping = 1,
     RequestDownstream = 2,
     RequestUpstream = 3,
-    SendBuffer = 4,
-    Complete = 5,
     Ready = 6,
     Decline = 7,
+    SocketOptions = 8,
+    TcpStats = 9,
+    CpuUsage = 10,
+    Session = 11,
+    Attach = 12,
+    Receipt = 13,
+    /// The client stops the running test early, sent on Ctrl-C.
+    Abort = 14,
     Close = 100,
 }
 
@@ -32,10 +173,15 @@ impl From<Command> for u8 {
             Command::Ping => 1,
             Command::RequestDownstream => 2,
             Command::RequestUpstream => 3,
-            Command::SendBuffer => 4,
-            Command::Complete => 5,
             Command::Ready => 6,
             Command::Decline => 7,
+            Command::SocketOptions => 8,
+            Command::TcpStats => 9,
+            Command::CpuUsage => 10,
+            Command::Session => 11,
+            Command::Attach => 12,
+            Command::Receipt => 13,
+            Command::Abort => 14,
             Command::Close => 100,
         }
     }
@@ -48,24 +194,132 @@ impl TryFrom<u8> for Command {
             1 => Ok(Command::Ping),
             2 => Ok(Command::RequestDownstream),
             3 => Ok(Command::RequestUpstream),
-            4 => Ok(Command::SendBuffer),
-            5 => Ok(Command::Complete),
             6 => Ok(Command::Ready),
             7 => Ok(Command::Decline),
+            8 => Ok(Command::SocketOptions),
+            9 => Ok(Command::TcpStats),
+            10 => Ok(Command::CpuUsage),
+            11 => Ok(Command::Session),
+            12 => Ok(Command::Attach),
+            13 => Ok(Command::Receipt),
+            14 => Ok(Command::Abort),
             100 => Ok(Command::Close),
             _ => Err(anyhow!("Invalid number {} for command", n)),
         }
     }
 }
 
+/// Upper bound of `Transfer::block_size` and of a file payload, so neither makes the server
+/// allocate more than a buffer. A random payload always takes its fixed size pattern
+/// (`RANDOM_PATTERN_SIZE`, 8 MiB) per direction, plus a copy in a memfd with zero-copy.
+pub const MAX_BLOCK_SIZE: usize = crate::BUFFER_SIZE;
+
+/// Upper bound of `Transfer::duration` and `Transfer::omit`, the limit of the command line.
+pub const MAX_DURATION: Duration = Duration::from_secs(10);
+
+/// How long a stopped receiver keeps draining the data phase.
+const STOP_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Size of the length prefix of a data frame.
+const FRAME_HEADER_SIZE: usize = 4;
+
+/// Splits the data phase into payload.
+/// The data phase is a stream of `[length: u32][payload]` frames terminated by a zero length frame,
+/// so the receiver can consume arbitrary large reads and stop exactly at the end marker.
+#[derive(Default)]
+struct FrameDecoder {
+    /// Payload bytes left in the current frame.
+    remaining: usize,
+    header: [u8; FRAME_HEADER_SIZE],
+    header_filled: usize,
+}
+
+impl FrameDecoder {
+    /// Feed received bytes and return how many of them belong to the data phase
+    /// and whether the end marker was reached. Bytes after the end marker are not consumed.
+    /// `on_payload` is told whether the slice completes its frame.
+    fn decode(
+        &mut self,
+        mut chunk: &[u8],
+        mut on_payload: impl FnMut(&[u8], bool),
+    ) -> (usize, bool) {
+        let mut consumed = 0;
+        while !chunk.is_empty() {
+            let take = if self.remaining > 0 {
+                let take = self.remaining.min(chunk.len());
+                self.remaining -= take;
+                on_payload(&chunk[..take], self.remaining == 0);
+                take
+            } else {
+                let take = (FRAME_HEADER_SIZE - self.header_filled).min(chunk.len());
+                self.header[self.header_filled..self.header_filled + take]
+                    .copy_from_slice(&chunk[..take]);
+                self.header_filled += take;
+                take
+            };
+            consumed += take;
+            chunk = &chunk[take..];
+
+            if self.header_filled == FRAME_HEADER_SIZE {
+                self.header_filled = 0;
+                self.remaining = u32::from_be_bytes(self.header) as usize;
+                if self.remaining == 0 {
+                    return (consumed, true);
+                }
+            }
+        }
+        (consumed, false)
+    }
+}
+
 pub struct Operator {
-    conn: TcpStream,
+    conn: BufReader<TcpStream>,
+    /// Payload bytes moved by the data loops so far, for live progress.
+    progress: Option<Arc<AtomicU64>>,
+    /// Ends the data loops early once set.
+    stop: Option<Arc<AtomicBool>>,
+    /// Serializes requests with writers on a clone of the connection.
+    write_lock: Arc<Mutex<()>>,
 }
 
 impl Operator {
     pub fn new(conn: TcpStream) -> Self {
-        Self { conn }
+        Self {
+            conn: BufReader::with_capacity(crate::BUFFER_SIZE, conn),
+            progress: None,
+            stop: None,
+            write_lock: Arc::new(Mutex::new(())),
+        }
     }
+
+    /// Count payload bytes sent or received by the data loops into `counter`.
+    pub fn track_progress(&mut self, counter: Arc<AtomicU64>) {
+        self.progress = Some(counter);
+    }
+
+    /// Stop the data loops once `flag` is set. Sending ends the transfer with the end marker,
+    /// receiving keeps the result so far.
+    pub fn stop_on(&mut self, flag: Arc<AtomicBool>) {
+        self.stop = Some(flag);
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop
+            .as_ref()
+            .is_some_and(|stop| stop.load(Ordering::Relaxed))
+    }
+
+    fn add_progress(&self, bytes: usize) {
+        if let Some(progress) = self.progress.as_ref() {
+            progress.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes bypass the read buffer and go straight to the socket.
+    fn writer(&self) -> &TcpStream {
+        self.conn.get_ref()
+    }
+
     pub fn ping_write_then_read(&mut self) -> Result<()> {
         self.write_ping().and(self.read_ping())
     }
@@ -74,6 +328,51 @@ impl Operator {
         self.read_ping().and(self.write_ping())
     }
 
+    /// First message of the control connection.
+    pub fn open_session(&mut self) -> Result<()> {
+        self.write(Command::Session)
+            .and_then(|_| self.write_version())
+            .and_then(|_| self.flush())
+    }
+
+    fn write_version(&mut self) -> Result<()> {
+        self.writer()
+            .write_u16::<BigEndian>(PROTOCOL_VERSION)
+            .map_err(anyhow::Error::from)
+    }
+
+    pub fn read_version(&mut self) -> Result<u16> {
+        Read::by_ref(&mut self.conn)
+            .read_u16::<BigEndian>()
+            .map_err(anyhow::Error::from)
+    }
+
+    /// First message of a data connection, `token` is given by the server with `Ready`.
+    pub fn attach(&mut self, token: u64) -> Result<()> {
+        self.write(Command::Attach)
+            .and_then(|_| self.write_token(token))
+            .and_then(|_| self.flush())
+    }
+
+    pub fn write_ready(&mut self, token: u64) -> Result<()> {
+        self.write(Command::Ready)
+            .and_then(|_| self.write_version())
+            .and_then(|_| self.write_token(token))
+            .and_then(|_| self.flush())
+    }
+
+    fn write_token(&mut self, token: u64) -> Result<()> {
+        self.writer()
+            .write_u64::<BigEndian>(token)
+            .map_err(anyhow::Error::from)
+    }
+
+    pub fn read_token(&mut self) -> Result<u64> {
+        Read::by_ref(&mut self.conn)
+            .read_u64::<BigEndian>()
+            .map_err(anyhow::Error::from)
+    }
+
     fn write_ping(&mut self) -> Result<()> {
         self.write(Command::Ping)
     }
@@ -82,62 +381,256 @@ impl Operator {
         self.expect(Command::Ping)
     }
 
-    pub fn request_downstream(&mut self, duration: Duration) -> Result<()> {
-        self.write(Command::RequestDownstream)
-            .and_then(|_| self.write_duration(duration))
-            .and_then(|_| self.flush())
+    pub fn request_downstream(&mut self, transfer: &Transfer) -> Result<()> {
+        self.write_message(|operator| {
+            operator
+                .write(Command::RequestDownstream)
+                .and_then(|_| operator.write_transfer(transfer))
+        })
     }
 
-    pub fn request_upstream(&mut self, duration: Duration) -> Result<()> {
-        self.write(Command::RequestUpstream)
-            .and_then(|_| self.write_duration(duration))
-            .and_then(|_| self.flush())
+    pub fn request_upstream(&mut self, transfer: &Transfer) -> Result<()> {
+        // the server only receives, the file contents stay on the client.
+        let transfer = match transfer.payload {
+            Payload::Data(_) => Transfer {
+                payload: Payload::Zeros,
+                ..transfer.clone()
+            },
+            _ => transfer.clone(),
+        };
+        self.write_message(|operator| {
+            operator
+                .write(Command::RequestUpstream)
+                .and_then(|_| operator.write_transfer(&transfer))
+        })
+    }
+
+    pub fn request_socket_options(&mut self, options: &SocketOptions) -> Result<()> {
+        self.write_message(|operator| {
+            operator
+                .write(Command::SocketOptions)
+                .and_then(|_| operator.write_socket_options(options))
+        })
+    }
+
+    /// Write a message made of several writes while holding `write_lock`,
+    /// so writers on a clone of the connection can't interleave with it.
+    fn write_message(&mut self, write: impl FnOnce(&mut Self) -> Result<()>) -> Result<()> {
+        let lock = Arc::clone(&self.write_lock);
+        let _guard = lock.lock().unwrap();
+        write(self).and_then(|_| self.flush())
+    }
+
+    /// Lock held while a request is written, see `write_message`.
+    pub fn write_lock(&self) -> Arc<Mutex<()>> {
+        Arc::clone(&self.write_lock)
+    }
+
+    /// Bytes received on the connection but not read yet.
+    pub fn buffered(&self) -> &[u8] {
+        self.conn.buffer()
+    }
+
+    pub fn socket(&self) -> SockRef<'_> {
+        SockRef::from(self.conn.get_ref())
     }
 
-    pub fn write_loop(&mut self, timeout: Duration) -> Result<u64> {
+    /// `TCP_INFO` of the underlying connection, if the platform provides it.
+    pub fn tcp_stats(&self) -> Option<TcpStats> {
+        TcpStats::read(self.conn.get_ref()).ok()
+    }
+
+    pub fn write_loop(&mut self, transfer: &Transfer) -> Result<u64> {
+        let block_size = transfer.block_size as usize;
+        let pattern = transfer.payload.pattern(block_size);
+        let timeout = transfer
+            .omit
+            .checked_add(transfer.duration)
+            .ok_or_else(|| anyhow!("Transfer duration overflows"))?;
+        if transfer.verify {
+            if transfer.mode == SendMode::ZeroCopy {
+                warn!("Zero-copy can not stamp blocks for verification, fall back to copy");
+            }
+            return self.write_loop_verify(timeout, block_size, &pattern);
+        }
+        if transfer.mode == SendMode::ZeroCopy {
+            #[cfg(target_os = "linux")]
+            match SendFile::new(&pattern) {
+                Ok(source) => return self.write_loop_zerocopy(timeout, block_size, &source),
+                Err(err) => warn!("Zero-copy unavailable, fall back to copy: {}", err),
+            }
+            #[cfg(not(target_os = "linux"))]
+            warn!("Zero-copy is only supported on Linux, fall back to copy");
+        }
+
         let start = time::Instant::now();
         let mut write_bytes = 0u64;
-        let buff = [0u8; crate::BUFFER_SIZE];
+        let mut cursor = Cursor::new(pattern.len());
+        // coalesce headers and small blocks into large writes.
+        let mut writer = BufWriter::with_capacity(crate::BUFFER_SIZE, self.writer());
         loop {
-            if start.elapsed() >= timeout {
+            if start.elapsed() >= timeout || self.stopped() {
                 break;
             }
-            self.send_buffer(&buff)?;
-            write_bytes = write_bytes.saturating_add(crate::BUFFER_SIZE as u64);
+            writer.write_u32::<BigEndian>(block_size as u32)?;
+            cursor.advance(block_size, |range| writer.write_all(&pattern[range]))?;
+            write_bytes = write_bytes.saturating_add(block_size as u64);
+            self.add_progress(block_size);
         }
-        self.write(Command::Complete)?;
+        writer.write_u32::<BigEndian>(0)?;
+        writer.flush()?;
         Ok(write_bytes)
     }
 
-    pub fn read_loop(&mut self) -> Result<u64> {
-        let mut buff = [0u8; crate::BUFFER_SIZE];
-        let mut read_bytes = 0u64;
+    /// Like the copy loop, but every block is copied out of the pattern and stamped.
+    fn write_loop_verify(
+        &mut self,
+        timeout: Duration,
+        block_size: usize,
+        pattern: &[u8],
+    ) -> Result<u64> {
+        let start = time::Instant::now();
+        let mut write_bytes = 0u64;
+        let mut cursor = Cursor::new(pattern.len());
+        let mut stamper = BlockStamper::default();
+        let mut block = vec![0u8; block_size];
+        let mut writer = BufWriter::with_capacity(crate::BUFFER_SIZE, self.writer());
         loop {
-            match self.read()? {
-                Command::SendBuffer => {
-                    self.receive_buffer(&mut buff)?;
-                    read_bytes = read_bytes.saturating_add(crate::BUFFER_SIZE as u64);
-                }
-                Command::Complete => return Ok(read_bytes),
-                _ => return Err(anyhow!("Unexpected command")),
+            if start.elapsed() >= timeout || self.stopped() {
+                break;
             }
+            let mut filled = 0;
+            cursor.advance(block_size, |range| {
+                block[filled..filled + range.len()].copy_from_slice(&pattern[range.clone()]);
+                filled += range.len();
+                Ok(())
+            })?;
+            stamper.stamp(&mut block);
+            writer.write_u32::<BigEndian>(block_size as u32)?;
+            writer.write_all(&block)?;
+            write_bytes = write_bytes.saturating_add(block_size as u64);
+            self.add_progress(block_size);
         }
+        writer.write_u32::<BigEndian>(0)?;
+        writer.flush()?;
+        Ok(write_bytes)
     }
 
-    pub fn send_buffer(&mut self, buff: &[u8]) -> Result<()> {
-        self.write(Command::SendBuffer)?;
-        Write::by_ref(&mut self.conn).write_all(buff)?;
-        self.flush()
+    #[cfg(target_os = "linux")]
+    fn write_loop_zerocopy(
+        &mut self,
+        timeout: Duration,
+        block_size: usize,
+        source: &SendFile,
+    ) -> Result<u64> {
+        let start = time::Instant::now();
+        let mut write_bytes = 0u64;
+        let mut cursor = Cursor::new(source.len());
+        let mut conn = self.writer();
+        loop {
+            if start.elapsed() >= timeout || self.stopped() {
+                break;
+            }
+            conn.write_u32::<BigEndian>(block_size as u32)?;
+            cursor.advance(block_size, |range| source.send_to(conn, range))?;
+            write_bytes = write_bytes.saturating_add(block_size as u64);
+            self.add_progress(block_size);
+        }
+        conn.write_u32::<BigEndian>(0)?;
+        Ok(write_bytes)
     }
 
-    pub fn receive_buffer(&mut self, buff: &mut [u8]) -> Result<()> {
-        Read::by_ref(&mut self.conn)
-            .read_exact(buff)
-            .map_err(anyhow::Error::from)
+    /// Receive the data phase. Bytes arriving within `transfer.omit` of the first byte are reported separately.
+    /// Once stopped, the result so far is kept and the rest is drained until the end marker,
+    /// for at most `STOP_DRAIN_TIMEOUT`, so the connection can be closed without a reset.
+    pub fn read_loop(&mut self, transfer: &Transfer) -> Result<Receipt> {
+        let omit = transfer.omit;
+        let mut verifier = if transfer.verify {
+            Some(BlockVerifier::default())
+        } else {
+            None
+        };
+        let progress = self.progress.clone();
+        let stop = self.stop.clone();
+        let mut decoder = FrameDecoder::default();
+        let mut read_bytes = 0u64;
+        let mut first = None;
+        let mut measure_from = None;
+        let mut omitted = (0u64, Duration::from_secs(0));
+        let mut stopped: Option<Receipt> = None;
+        let mut drain_deadline = None;
+        loop {
+            // a stopped receiver gives up draining on timeout or close.
+            let chunk = match self.conn.fill_buf() {
+                Ok(chunk) => chunk,
+                Err(err) => return stopped.ok_or_else(|| err.into()),
+            };
+            if chunk.is_empty() {
+                return stopped.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let now = time::Instant::now();
+            let first = *first.get_or_insert(now);
+            let stopping = stopped.is_none()
+                && stop
+                    .as_ref()
+                    .is_some_and(|stop| stop.load(Ordering::Relaxed));
+            if stopping {
+                stopped = Some(Receipt::measured(
+                    read_bytes,
+                    now,
+                    first,
+                    measure_from,
+                    omitted,
+                    verifier.as_ref().map(|verifier| verifier.integrity()),
+                ));
+            }
+            if stopped.is_none() && measure_from.is_none() && now - first >= omit {
+                omitted = (read_bytes, now - first);
+                read_bytes = 0;
+                measure_from = Some(now);
+            }
+            let counting = stopped.is_none();
+            let (consumed, complete) = decoder.decode(chunk, |payload, frame_end| {
+                if !counting {
+                    return;
+                }
+                if let Some(verifier) = verifier.as_mut() {
+                    verifier.verify(payload, frame_end);
+                }
+                if let Some(progress) = progress.as_ref() {
+                    progress.fetch_add(payload.len() as u64, Ordering::Relaxed);
+                }
+                read_bytes = read_bytes.saturating_add(payload.len() as u64)
+            });
+            self.conn.consume(consumed);
+            if stopping {
+                self.conn
+                    .get_ref()
+                    .set_read_timeout(Some(STOP_DRAIN_TIMEOUT))?;
+                drain_deadline = Some(now + STOP_DRAIN_TIMEOUT);
+            }
+            // a sender that keeps sending is not drained past the deadline.
+            let drained = drain_deadline.is_some_and(|deadline| now >= deadline);
+            if complete || drained {
+                if let Some(receipt) = stopped {
+                    self.conn.get_ref().set_read_timeout(None)?;
+                    return Ok(receipt);
+                }
+                let integrity = verifier.map(|verifier| verifier.integrity());
+                return Ok(Receipt::measured(
+                    read_bytes,
+                    now,
+                    first,
+                    measure_from,
+                    omitted,
+                    integrity,
+                ));
+            }
+        }
     }
 
     pub fn write(&mut self, cmd: Command) -> Result<()> {
-        Write::by_ref(&mut self.conn)
+        self.writer()
             .write_u8(cmd.into())
             .map_err(anyhow::Error::from)
     }
@@ -147,7 +640,7 @@ impl Operator {
     }
 
     pub fn write_duration(&mut self, duration: Duration) -> Result<()> {
-        Write::by_ref(&mut self.conn)
+        self.writer()
             .write_u64::<BigEndian>(duration.as_secs())
             .map_err(anyhow::Error::from)
     }
@@ -159,9 +652,246 @@ impl Operator {
             .map(Duration::from_secs)
     }
 
+    pub fn write_transfer(&mut self, transfer: &Transfer) -> Result<()> {
+        self.write_duration(transfer.duration)?;
+        self.write_duration(transfer.omit)?;
+        self.write_send_mode(transfer.mode)?;
+        let mut conn = self.writer();
+        conn.write_u32::<BigEndian>(transfer.block_size)?;
+        match &transfer.payload {
+            Payload::Zeros => conn.write_u8(0)?,
+            Payload::Random(seed) => {
+                conn.write_u8(1)?;
+                conn.write_u64::<BigEndian>(*seed)?;
+            }
+            Payload::Data(data) => {
+                conn.write_u8(2)?;
+                conn.write_u32::<BigEndian>(data.len() as u32)?;
+                conn.write_all(data)?;
+            }
+        }
+        conn.write_u8(transfer.verify as u8)
+            .map_err(anyhow::Error::from)
+    }
+
+    pub fn read_transfer(&mut self) -> Result<Transfer> {
+        let duration = self.read_duration()?;
+        let omit = self.read_duration()?;
+        if duration > MAX_DURATION || omit > MAX_DURATION {
+            return Err(anyhow!(
+                "Invalid duration {:?} or omit {:?} (max: {:?})",
+                duration,
+                omit,
+                MAX_DURATION
+            ));
+        }
+        let mode = self.read_send_mode()?;
+        let block_size = Read::by_ref(&mut self.conn).read_u32::<BigEndian>()?;
+        if block_size == 0 || block_size as usize > MAX_BLOCK_SIZE {
+            return Err(anyhow!("Invalid block size {}", block_size));
+        }
+        let conn = Read::by_ref(&mut self.conn);
+        let payload = match conn.read_u8()? {
+            0 => Payload::Zeros,
+            1 => Payload::Random(conn.read_u64::<BigEndian>()?),
+            2 => {
+                let len = conn.read_u32::<BigEndian>()? as usize;
+                if len == 0 || len > MAX_BLOCK_SIZE {
+                    return Err(anyhow!("Invalid payload size {}", len));
+                }
+                let mut data = vec![0u8; len];
+                conn.read_exact(&mut data)?;
+                Payload::Data(Arc::new(data))
+            }
+            n => return Err(anyhow!("Invalid number {} for payload", n)),
+        };
+        let verify = conn.read_u8()? != 0;
+        if verify && (block_size as usize) < BLOCK_HEADER_SIZE {
+            return Err(anyhow!(
+                "Block size {} too small to verify, at least {} bytes required",
+                block_size,
+                BLOCK_HEADER_SIZE
+            ));
+        }
+        Ok(Transfer {
+            duration,
+            omit,
+            mode,
+            block_size,
+            payload,
+            verify,
+        })
+    }
+
+    pub fn write_socket_options(&mut self, options: &SocketOptions) -> Result<()> {
+        // unset values are encoded as zero.
+        let mut conn = self.writer();
+        conn.write_u32::<BigEndian>(options.send_buffer.unwrap_or(0))?;
+        conn.write_u32::<BigEndian>(options.recv_buffer.unwrap_or(0))?;
+        conn.write_u8(match options.nodelay {
+            None => 0,
+            Some(false) => 1,
+            Some(true) => 2,
+        })?;
+        conn.write_u32::<BigEndian>(options.mss.unwrap_or(0))?;
+        let congestion = options.congestion.as_deref().unwrap_or("").as_bytes();
+        if congestion.len() > u8::MAX as usize {
+            return Err(anyhow!("Congestion control name too long"));
+        }
+        conn.write_u8(congestion.len() as u8)?;
+        conn.write_all(congestion).map_err(anyhow::Error::from)
+    }
+
+    pub fn read_socket_options(&mut self) -> Result<SocketOptions> {
+        let non_zero = |n: u32| if n == 0 { None } else { Some(n) };
+        let conn = Read::by_ref(&mut self.conn);
+        let send_buffer = non_zero(conn.read_u32::<BigEndian>()?);
+        let recv_buffer = non_zero(conn.read_u32::<BigEndian>()?);
+        let nodelay = match conn.read_u8()? {
+            0 => None,
+            1 => Some(false),
+            _ => Some(true),
+        };
+        let mss = non_zero(conn.read_u32::<BigEndian>()?);
+        let mut congestion = vec![0u8; conn.read_u8()? as usize];
+        conn.read_exact(&mut congestion)?;
+        Ok(SocketOptions {
+            send_buffer,
+            recv_buffer,
+            nodelay,
+            mss,
+            congestion: if congestion.is_empty() {
+                None
+            } else {
+                Some(String::from_utf8(congestion)?)
+            },
+        })
+    }
+
+    /// Effective options of a data socket, sent by the server after the data phase.
+    pub fn write_socket_options_report(&mut self, options: &SocketOptions) -> Result<()> {
+        self.write(Command::SocketOptions)?;
+        self.write_socket_options(options)
+    }
+
+    pub fn read_socket_options_report(&mut self) -> Result<SocketOptions> {
+        self.expect(Command::SocketOptions)?;
+        self.read_socket_options()
+    }
+
+    pub fn write_send_mode(&mut self, mode: SendMode) -> Result<()> {
+        self.writer()
+            .write_u8(mode.into())
+            .map_err(anyhow::Error::from)
+    }
+
+    pub fn read_send_mode(&mut self) -> Result<SendMode> {
+        SendMode::try_from(Read::by_ref(&mut self.conn).read_u8()?)
+    }
+
+    /// `utilization` is the fraction of one core, `None` when unknown.
+    pub fn write_cpu_usage(&mut self, utilization: Option<f64>) -> Result<()> {
+        self.write(Command::CpuUsage)?;
+        let mut conn = self.writer();
+        match utilization {
+            Some(utilization) => {
+                conn.write_u8(1)?;
+                conn.write_f64::<BigEndian>(utilization)?;
+            }
+            None => conn.write_u8(0)?,
+        }
+        self.flush()
+    }
+
+    pub fn read_cpu_usage(&mut self) -> Result<Option<f64>> {
+        self.expect(Command::CpuUsage)?;
+        let conn = Read::by_ref(&mut self.conn);
+        if conn.read_u8()? == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(conn.read_f64::<BigEndian>()?))
+        }
+    }
+
+    pub fn write_receipt(&mut self, receipt: &Receipt) -> Result<()> {
+        self.write(Command::Receipt)?;
+        let mut conn = self.writer();
+        conn.write_u64::<BigEndian>(receipt.bytes)?;
+        conn.write_u64::<BigEndian>(receipt.elapsed.as_nanos() as u64)?;
+        conn.write_u64::<BigEndian>(receipt.omitted_bytes)?;
+        conn.write_u64::<BigEndian>(receipt.omitted_elapsed.as_nanos() as u64)?;
+        match receipt.integrity {
+            Some(integrity) => {
+                conn.write_u8(1)?;
+                conn.write_u64::<BigEndian>(integrity.blocks)?;
+                conn.write_u64::<BigEndian>(integrity.corrupted)?;
+                conn.write_u64::<BigEndian>(integrity.missing)?;
+                conn.write_u64::<BigEndian>(integrity.reordered)?;
+            }
+            None => conn.write_u8(0)?,
+        }
+        self.flush()
+    }
+
+    pub fn read_receipt(&mut self) -> Result<Receipt> {
+        self.expect(Command::Receipt)?;
+        let conn = Read::by_ref(&mut self.conn);
+        Ok(Receipt {
+            bytes: conn.read_u64::<BigEndian>()?,
+            elapsed: Duration::from_nanos(conn.read_u64::<BigEndian>()?),
+            omitted_bytes: conn.read_u64::<BigEndian>()?,
+            omitted_elapsed: Duration::from_nanos(conn.read_u64::<BigEndian>()?),
+            integrity: if conn.read_u8()? == 0 {
+                None
+            } else {
+                Some(Integrity {
+                    blocks: conn.read_u64::<BigEndian>()?,
+                    corrupted: conn.read_u64::<BigEndian>()?,
+                    missing: conn.read_u64::<BigEndian>()?,
+                    reordered: conn.read_u64::<BigEndian>()?,
+                })
+            },
+        })
+    }
+
+    pub fn write_tcp_stats(&mut self, stats: Option<&TcpStats>) -> Result<()> {
+        self.write(Command::TcpStats)?;
+        let mut conn = self.writer();
+        match stats {
+            Some(stats) => {
+                conn.write_u8(1)?;
+                conn.write_u32::<BigEndian>(stats.retransmits)?;
+                conn.write_u32::<BigEndian>(stats.rtt)?;
+                conn.write_u32::<BigEndian>(stats.rtt_var)?;
+                conn.write_u32::<BigEndian>(stats.cwnd)?;
+                conn.write_u64::<BigEndian>(stats.pacing_rate)?;
+            }
+            None => conn.write_u8(0)?,
+        }
+        self.flush()
+    }
+
+    pub fn read_tcp_stats(&mut self) -> Result<Option<TcpStats>> {
+        self.expect(Command::TcpStats)?;
+        let conn = Read::by_ref(&mut self.conn);
+        if conn.read_u8()? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(TcpStats {
+            retransmits: conn.read_u32::<BigEndian>()?,
+            rtt: conn.read_u32::<BigEndian>()?,
+            rtt_var: conn.read_u32::<BigEndian>()?,
+            cwnd: conn.read_u32::<BigEndian>()?,
+            pacing_rate: conn.read_u64::<BigEndian>()?,
+        }))
+    }
+
     pub fn expect(&mut self, expect: Command) -> Result<()> {
         let actual = Command::try_from(Read::by_ref(&mut self.conn).read_u8()?)?;
-        if actual != expect {
+        if actual == Command::Decline && expect != Command::Decline {
+            let reason = self.read_decline_reason()?;
+            Err(anyhow!("Server ended the session: {}", reason))
+        } else if actual != expect {
             Err(anyhow!(
                 "Unexpected command. expect: {:?}, actual: {:?}",
                 expect,
@@ -178,7 +908,7 @@ impl Operator {
             .and(self.flush())?;
 
         if shutdown {
-            self.conn
+            self.writer()
                 .shutdown(Shutdown::Both)
                 .map_err(anyhow::Error::from)
         } else {
@@ -195,9 +925,11 @@ impl Operator {
                 v += max_threads as u64;
                 v
             }
+            DeclineReason::Cancelled => 2 << 32,
+            DeclineReason::VersionMismatch(version) => (3 << 32) + version as u64,
             DeclineReason::Unknown => 0,
         };
-        Write::by_ref(&mut self.conn)
+        self.writer()
             .write_u64::<BigEndian>(v)
             .map_err(anyhow::Error::from)
     }
@@ -207,17 +939,71 @@ impl Operator {
             .read_u64::<BigEndian>()
             .map_err(anyhow::Error::from)?;
         let reason = v >> 32;
-        let detail = v & (std::u32::MAX as u64);
-        if reason == 1 {
-            Ok(DeclineReason::MaxThreadsExceed(detail as u32))
-        } else {
-            Ok(DeclineReason::Unknown)
+        let detail = v & (u32::MAX as u64);
+        match reason {
+            1 => Ok(DeclineReason::MaxThreadsExceed(detail as u32)),
+            2 => Ok(DeclineReason::Cancelled),
+            3 => Ok(DeclineReason::VersionMismatch(detail as u16)),
+            _ => Ok(DeclineReason::Unknown),
         }
     }
 
     fn flush(&mut self) -> Result<()> {
-        Write::by_ref(&mut self.conn)
-            .flush()
-            .map_err(anyhow::Error::from)
+        self.writer().flush().map_err(anyhow::Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Payload, frame ends reported by the decoder, consumed bytes and completion.
+    fn decode(decoder: &mut FrameDecoder, chunk: &[u8]) -> (Vec<u8>, Vec<bool>, usize, bool) {
+        let mut payload = Vec::new();
+        let mut ends = Vec::new();
+        let (consumed, complete) = decoder.decode(chunk, |bytes, frame_end| {
+            payload.extend_from_slice(bytes);
+            ends.push(frame_end);
+        });
+        (payload, ends, consumed, complete)
+    }
+
+    #[test]
+    fn frame_header_split_across_reads() {
+        let mut decoder = FrameDecoder::default();
+        assert_eq!(decode(&mut decoder, &[0, 0]), (vec![], vec![], 2, false));
+        assert_eq!(
+            decode(&mut decoder, &[0, 3, 7, 8]),
+            (vec![7, 8], vec![false], 4, false)
+        );
+        assert_eq!(
+            decode(&mut decoder, &[9, 0, 0, 0]),
+            (vec![9], vec![true], 4, false)
+        );
+        // bytes after the end marker are left to the caller.
+        assert_eq!(decode(&mut decoder, &[0, 42]), (vec![], vec![], 1, true));
+    }
+
+    #[test]
+    fn end_marker_after_partial_frame() {
+        let mut decoder = FrameDecoder::default();
+        assert_eq!(
+            decode(&mut decoder, &[0, 0, 0, 6, 0, 0, 0]),
+            (vec![0, 0, 0], vec![false], 7, false)
+        );
+        // zero payload bytes must not be taken for the end marker.
+        assert_eq!(
+            decode(&mut decoder, &[0, 0, 0, 0, 0, 0, 0, 1]),
+            (vec![0, 0, 0], vec![true], 7, true)
+        );
+    }
+
+    #[test]
+    fn frames_in_one_read() {
+        let mut decoder = FrameDecoder::default();
+        assert_eq!(
+            decode(&mut decoder, &[0, 0, 0, 1, 5, 0, 0, 0, 2, 6, 7, 0, 0, 0, 0]),
+            (vec![5, 6, 7], vec![true, true], 15, true)
+        );
     }
 }
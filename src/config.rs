@@ -0,0 +1,123 @@
+use crate::{client::Format, Result};
+use anyhow::{anyhow, Context};
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// Read by `netspeed server` unless `--config` is given.
+pub const SERVER_CONFIG_PATH: &str = "/etc/netspeed/server.toml";
+
+/// Defaults read from a TOML file. Command line flags take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub client: ClientConfig,
+    pub server: ServerConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub addr: Option<String>,
+    pub server_list: Option<String>,
+    /// Seconds.
+    pub duration: Option<u64>,
+    /// Seconds.
+    pub omit: Option<u64>,
+    pub block_size: Option<u32>,
+    pub send_buffer: Option<u32>,
+    pub recv_buffer: Option<u32>,
+    pub no_delay: Option<bool>,
+    pub mss: Option<u32>,
+    pub congestion: Option<String>,
+    pub format: Option<Format>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub addr: Option<String>,
+    pub max_threads: Option<u32>,
+    pub announce: Option<bool>,
+    pub admin_socket: Option<String>,
+}
+
+impl Config {
+    /// Load `path`, or the default location if `path` is `None`.
+    /// A missing file at the default location is not an error.
+    pub fn load(path: Option<&str>, is_server: bool) -> Result<Self> {
+        match path {
+            Some(path) => Config::read(Path::new(path)),
+            None => match Config::default_path(is_server) {
+                Some(path) if path.exists() => Config::read(&path),
+                _ => Ok(Config::default()),
+            },
+        }
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        let content =
+            fs::read_to_string(path).with_context(|| format!("Read config {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Parse config {}", path.display()))
+    }
+
+    /// `/etc/netspeed/server.toml` for the server, `~/.config/netspeed/config.toml` otherwise.
+    fn default_path(is_server: bool) -> Option<PathBuf> {
+        if is_server {
+            return Some(PathBuf::from(SERVER_CONFIG_PATH));
+        }
+        env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .map(|dir| dir.join("netspeed").join("config.toml"))
+    }
+}
+
+/// Value of `name` given on the command line, else `configured`, else the default of the flag.
+pub fn resolve(args: &ArgMatches, name: &str, configured: Option<impl ToString>) -> Option<String> {
+    if args.occurrences_of(name) > 0 {
+        args.value_of(name).map(String::from)
+    } else {
+        configured
+            .map(|value| value.to_string())
+            .or_else(|| args.value_of(name).map(String::from))
+    }
+}
+
+/// Like `resolve`, but a value from the config must pass `validate`, the check of the flag.
+pub fn resolve_checked(
+    args: &ArgMatches,
+    name: &str,
+    configured: Option<impl ToString>,
+    validate: fn(String) -> std::result::Result<(), String>,
+) -> Result<Option<String>> {
+    if args.occurrences_of(name) == 0 {
+        if let Some(value) = configured.as_ref().map(|value| value.to_string()) {
+            validate(value).map_err(|err| {
+                anyhow!("Invalid `{}` in config: {}", name.replace('-', "_"), err)
+            })?;
+        }
+    }
+    Ok(resolve(args, name, configured))
+}
+
+/// Whether the flag `name` is enabled. `negated` on the command line turns off
+/// a flag enabled in the config.
+pub fn resolve_flag(
+    args: &ArgMatches,
+    name: &str,
+    negated: &str,
+    configured: Option<bool>,
+) -> bool {
+    if args.is_present(name) {
+        true
+    } else if args.is_present(negated) {
+        false
+    } else {
+        configured.unwrap_or(false)
+    }
+}
@@ -0,0 +1,46 @@
+use std::time::{Duration, Instant};
+
+/// Measures the CPU time spent by the calling thread relative to wall clock time.
+pub struct CpuTimer {
+    cpu: Option<Duration>,
+    wall: Instant,
+}
+
+impl CpuTimer {
+    pub fn start() -> Self {
+        Self {
+            cpu: thread_cpu_time(),
+            wall: Instant::now(),
+        }
+    }
+
+    /// Fraction of one core used since `start`. `None` if the platform cannot tell.
+    pub fn utilization(&self) -> Option<f64> {
+        let wall = self.wall.elapsed().as_secs_f64();
+        match (self.cpu, thread_cpu_time()) {
+            (Some(start), Some(now)) if wall > 0f64 => {
+                Some(now.saturating_sub(start).as_secs_f64() / wall)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn thread_cpu_time() -> Option<Duration> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safety: `ts` is a valid timespec for the kernel to fill.
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) } == 0 {
+        Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_cpu_time() -> Option<Duration> {
+    None
+}
@@ -0,0 +1,277 @@
+use crate::{
+    history::Record,
+    progress::{Observer, Tick},
+    util, Result,
+};
+use anyhow::Context;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Block, Gauge, Paragraph, Row, Sparkline, Table},
+    DefaultTerminal, Frame,
+};
+use std::{
+    collections::VecDeque,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// How often the screen is redrawn and keys are polled.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+/// Throughput samples kept for the rolling graphs, one per progress tick.
+const LIVE_SAMPLES: usize = 600;
+/// Completed tests kept for the latency graph and the table.
+const RECENT_RECORDS: usize = 100;
+
+#[derive(Default)]
+struct State {
+    /// Progress of the running test.
+    current: Option<Tick>,
+    downstream: VecDeque<u64>,
+    upstream: VecDeque<u64>,
+    records: VecDeque<Record>,
+    last_error: Option<String>,
+}
+
+/// Full-screen view of `netspeed monitor`, fed by the monitor's tests.
+pub struct Dashboard {
+    title: String,
+    state: Mutex<State>,
+    stop: Mutex<Option<mpsc::Sender<()>>>,
+    ui: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Dashboard {
+    /// Take over the terminal and start drawing. `records` are earlier tests from the history.
+    pub fn start(title: String, records: Vec<Record>) -> Result<Arc<Self>> {
+        let mut state = State::default();
+        for record in records {
+            push_bounded(&mut state.records, record, RECENT_RECORDS);
+        }
+        let dashboard = Arc::new(Dashboard {
+            title,
+            state: Mutex::new(state),
+            stop: Mutex::new(None),
+            ui: Mutex::new(None),
+        });
+
+        let terminal = ratatui::try_init().context("Initialize terminal")?;
+        let (stop, stopped) = mpsc::channel();
+        let ui = {
+            let dashboard = Arc::clone(&dashboard);
+            thread::spawn(move || dashboard.ui_loop(terminal, stopped))
+        };
+        *dashboard.stop.lock().unwrap() = Some(stop);
+        *dashboard.ui.lock().unwrap() = Some(ui);
+        Ok(dashboard)
+    }
+
+    /// Show the outcome of a test.
+    pub fn record(&self, result: &Result<Record>) {
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(record) => {
+                push_bounded(&mut state.records, record.clone(), RECENT_RECORDS);
+                state.last_error = None;
+            }
+            Err(err) => state.last_error = Some(format!("{:#}", err)),
+        }
+    }
+
+    /// Give the terminal back.
+    pub fn stop(&self) {
+        self.stop.lock().unwrap().take();
+        if let Some(ui) = self.ui.lock().unwrap().take() {
+            let _ = ui.join();
+        }
+    }
+
+    fn ui_loop(&self, mut terminal: DefaultTerminal, stopped: mpsc::Receiver<()>) {
+        let result = loop {
+            if let Err(mpsc::TryRecvError::Disconnected) = stopped.try_recv() {
+                break Ok(());
+            }
+            if let Err(err) = terminal.draw(|frame| self.draw(frame)) {
+                break Err(err);
+            }
+            match event::poll(REDRAW_INTERVAL) {
+                Ok(true) => match event::read() {
+                    Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                        let ctrl_c = key.code == KeyCode::Char('c')
+                            && key.modifiers.contains(KeyModifiers::CONTROL);
+                        if ctrl_c || key.code == KeyCode::Char('q') || key.code == KeyCode::Esc {
+                            ratatui::restore();
+                            std::process::exit(0);
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(err) => break Err(err),
+                },
+                Ok(false) => (),
+                Err(err) => break Err(err),
+            }
+        };
+        ratatui::restore();
+        if let Err(err) = result {
+            eprintln!("Dashboard: {}", err);
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let state = self.state.lock().unwrap();
+        let [current, graphs, latency, recent, footer] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Min(8),
+            Constraint::Length(5),
+            Constraint::Length(12),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [downstream, upstream] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(graphs);
+
+        self.draw_current(frame, current, &state);
+        draw_throughput(
+            frame,
+            downstream,
+            "Downstream",
+            &state.downstream,
+            Color::Cyan,
+        );
+        draw_throughput(frame, upstream, "Upstream", &state.upstream, Color::Magenta);
+        draw_latency(frame, latency, &state.records);
+        draw_recent(frame, recent, &state.records);
+
+        let footer_text = match state.last_error.as_ref() {
+            Some(err) => Line::styled(format!("Last test failed: {}", err), Color::Red),
+            None => Line::from("q: quit"),
+        };
+        frame.render_widget(Paragraph::new(footer_text), footer);
+    }
+
+    fn draw_current(&self, frame: &mut Frame, area: Rect, state: &State) {
+        let block = Block::bordered().title(self.title.as_str());
+        match state.current {
+            Some(tick) => {
+                let ratio = (tick.elapsed.as_secs_f64() / tick.total.as_secs_f64()).min(1f64);
+                let label = format!(
+                    "{} {:.1}/{} s {}",
+                    tick.label,
+                    tick.elapsed.as_secs_f64(),
+                    tick.total.as_secs(),
+                    util::format_bps(tick.bps)
+                );
+                frame.render_widget(
+                    Gauge::default().block(block).ratio(ratio).label(label),
+                    area,
+                );
+            }
+            None => {
+                let idle = match state.records.back() {
+                    Some(last) => format!(
+                        "Waiting for the next test, last at {}",
+                        last.timestamp.to_rfc3339()
+                    ),
+                    None => "Waiting for the first test".to_owned(),
+                };
+                frame.render_widget(Paragraph::new(idle).block(block), area);
+            }
+        }
+    }
+}
+
+impl Observer for Dashboard {
+    fn tick(&self, tick: &Tick) {
+        let mut state = self.state.lock().unwrap();
+        let samples = if tick.label == "Upstream" {
+            &mut state.upstream
+        } else {
+            &mut state.downstream
+        };
+        push_bounded(samples, tick.bps as u64, LIVE_SAMPLES);
+        state.current = Some(*tick);
+    }
+
+    fn finish(&self, _label: &'static str) {
+        self.state.lock().unwrap().current = None;
+    }
+}
+
+fn push_bounded<T>(queue: &mut VecDeque<T>, value: T, capacity: usize) {
+    if queue.len() == capacity {
+        queue.pop_front();
+    }
+    queue.push_back(value);
+}
+
+/// Rolling graph of the throughput samples, newest on the right.
+fn draw_throughput(
+    frame: &mut Frame,
+    area: Rect,
+    label: &str,
+    samples: &VecDeque<u64>,
+    color: Color,
+) {
+    let title = match samples.back() {
+        Some(bps) => format!("{} {}", label, util::format_bps(*bps as f64)),
+        None => label.to_owned(),
+    };
+    // the graph shows as many recent samples as fit.
+    let width = area.width.saturating_sub(2) as usize;
+    let visible = samples.iter().skip(samples.len().saturating_sub(width));
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::bordered().title(title))
+            .style(Style::default().fg(color))
+            .data(visible.copied()),
+        area,
+    );
+}
+
+fn draw_latency(frame: &mut Frame, area: Rect, records: &VecDeque<Record>) {
+    let title = match records.back() {
+        Some(record) => format!("Latency {}", util::format_latency(record.latency)),
+        None => "Latency".to_owned(),
+    };
+    let width = area.width.saturating_sub(2) as usize;
+    let visible = records.iter().skip(records.len().saturating_sub(width));
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::bordered().title(title))
+            .style(Style::default().fg(Color::Yellow))
+            .data(visible.map(|record| record.latency.as_micros() as u64)),
+        area,
+    );
+}
+
+fn draw_recent(frame: &mut Frame, area: Rect, records: &VecDeque<Record>) {
+    let rows = records.iter().rev().map(|record| {
+        Row::new(vec![
+            record.timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+            record.server.clone(),
+            util::format_bps(record.downstream_bps),
+            util::format_bps(record.upstream_bps),
+            util::format_latency(record.latency),
+        ])
+    });
+    let widths = [
+        Constraint::Length(19),
+        Constraint::Min(16),
+        Constraint::Length(14),
+        Constraint::Length(14),
+        Constraint::Length(10),
+    ];
+    frame.render_widget(
+        Table::new(rows, widths)
+            .header(
+                Row::new(vec!["Time", "Server", "Downstream", "Upstream", "Latency"])
+                    .style(Style::default().fg(Color::Gray)),
+            )
+            .block(Block::bordered().title("Recent tests")),
+        area,
+    );
+}
@@ -0,0 +1,111 @@
+use crate::Result;
+use anyhow::Context;
+use log::{debug, error, info};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fmt, io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// UDP port servers answer discovery probes on.
+pub const DISCOVERY_PORT: u16 = 5555;
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Broadcast by `discover`.
+const PROBE: &[u8] = b"NETSPEED-DISCOVER/1";
+/// Answer to a probe, followed by the server's TCP port as big endian u16.
+const ANNOUNCE: &[u8] = b"NETSPEED-SERVER/1";
+
+/// A server that answered the discovery probe.
+#[derive(Debug, Clone, Copy)]
+pub struct Discovered {
+    pub addr: SocketAddr,
+    /// Time until the server answered the probe.
+    pub latency: Duration,
+}
+
+impl fmt::Display for Discovered {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:.2} ms",
+            self.addr,
+            self.latency.as_secs_f64() * 1000f64
+        )
+    }
+}
+
+/// Answer discovery probes in the background, advertising the server listening on `tcp_port`.
+pub fn announce(tcp_port: u16) -> Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))
+        .with_context(|| format!("Bind discovery port {}", DISCOVERY_PORT))?;
+    info!("Announcing on udp port {}", DISCOVERY_PORT);
+    let mut answer = ANNOUNCE.to_vec();
+    answer.extend_from_slice(&tcp_port.to_be_bytes());
+    thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((n, from)) if &buf[..n] == PROBE => {
+                    debug!("Discovery probe from {}", from);
+                    if let Err(err) = socket.send_to(&answer, from) {
+                        error!("Answer discovery probe from {}: {}", from, err);
+                    }
+                }
+                Ok(_) => (),
+                Err(err) => {
+                    error!("Receive discovery probe: {}", err);
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Broadcast a probe and collect the servers answering within `timeout`, nearest first.
+pub fn discover(timeout: Duration) -> Result<Vec<Discovered>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    let start = Instant::now();
+    socket
+        .send_to(PROBE, (Ipv4Addr::BROADCAST, DISCOVERY_PORT))
+        .context("Broadcast discovery probe")?;
+
+    let mut found = HashMap::new();
+    let mut buf = [0u8; 64];
+    loop {
+        let remaining = timeout.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut =>
+            {
+                break
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let latency = start.elapsed();
+        match buf[..n].strip_prefix(ANNOUNCE) {
+            Some(port) if port.len() == 2 => {
+                let port = u16::from_be_bytes(port.try_into().unwrap());
+                let addr = SocketAddr::new(from.ip(), port);
+                debug!("Discovered {} in {:?}", addr, latency);
+                found.entry(addr).or_insert(Discovered { addr, latency });
+            }
+            _ => debug!("Ignore unexpected discovery answer from {}", from),
+        }
+    }
+
+    let mut found: Vec<Discovered> = found.into_values().collect();
+    found.sort_by_key(|server| server.latency);
+    Ok(found)
+}
@@ -0,0 +1,269 @@
+use crate::{util, Result};
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const HEADER: &str = "timestamp,server,downstream_bps,upstream_bps,latency_ms";
+
+/// One completed test as stored in the history.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub timestamp: DateTime<Utc>,
+    pub server: String,
+    pub downstream_bps: f64,
+    pub upstream_bps: f64,
+    pub latency: Duration,
+}
+
+impl Record {
+    fn to_csv(&self) -> String {
+        format!(
+            "{},{},{:.0},{:.0},{:.3}",
+            self.timestamp.to_rfc3339(),
+            self.server,
+            self.downstream_bps,
+            self.upstream_bps,
+            self.latency.as_secs_f64() * 1000f64
+        )
+    }
+
+    fn from_csv(line: &str) -> Result<Self> {
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() != 5 {
+            return Err(anyhow!("Expect 5 fields, got {}", fields.len()));
+        }
+        Ok(Record {
+            timestamp: DateTime::parse_from_rfc3339(fields[0])?.with_timezone(&Utc),
+            server: fields[1].to_owned(),
+            downstream_bps: fields[2].parse()?,
+            upstream_bps: fields[3].parse()?,
+            latency: Duration::from_secs_f64(fields[4].parse::<f64>()? / 1000f64),
+        })
+    }
+}
+
+/// Results of past tests kept as a CSV file.
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// `$XDG_DATA_HOME/netspeed/history.csv`, falling back to `~/.local/share`.
+    pub fn default_path() -> Option<PathBuf> {
+        env::var_os("XDG_DATA_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+            .map(|dir| dir.join("netspeed").join("history.csv"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&self, record: &Record) -> Result<()> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Open history {}", self.path.display()))?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{}", HEADER)?;
+        }
+        writeln!(file, "{}", record.to_csv())?;
+        Ok(())
+    }
+
+    /// Records taken at or after `since`, oldest first.
+    pub fn read(&self, since: Option<DateTime<Utc>>) -> Result<Vec<Record>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Read history {}", self.path.display()))
+            }
+        };
+        let mut records = Vec::new();
+        for (n, line) in content.lines().enumerate() {
+            if line.is_empty() || line == HEADER {
+                continue;
+            }
+            let record = Record::from_csv(line)
+                .with_context(|| format!("{}:{}", self.path.display(), n + 1))?;
+            if since.is_none_or(|since| record.timestamp >= since) {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// Distribution of one measured value.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub min: f64,
+    pub median: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+impl Stats {
+    pub fn of(values: impl IntoIterator<Item = f64>) -> Option<Self> {
+        let mut values: Vec<f64> = values.into_iter().collect();
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(|a, b| a.total_cmp(b));
+        let n = values.len();
+        let median = if n.is_multiple_of(2) {
+            (values[n / 2 - 1] + values[n / 2]) / 2f64
+        } else {
+            values[n / 2]
+        };
+        // nearest rank.
+        let p95 = values[((n as f64 * 0.95).ceil() as usize).max(1) - 1];
+        Some(Stats {
+            min: values[0],
+            median,
+            p95,
+            max: values[n - 1],
+        })
+    }
+}
+
+/// Print min/median/p95/max of the records.
+pub fn summarize<W: Write>(records: &[Record], mut writer: W) -> Result<()> {
+    let (first, last) = match (records.first(), records.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => {
+            writeln!(writer, "No records")?;
+            return Ok(());
+        }
+    };
+    writeln!(
+        writer,
+        "   Samples: {} ({} - {})",
+        records.len(),
+        first.timestamp.to_rfc3339(),
+        last.timestamp.to_rfc3339()
+    )?;
+    writeln!(
+        writer,
+        "{:>10}  {:>14} {:>14} {:>14} {:>14}",
+        "", "min", "median", "p95", "max"
+    )?;
+    write_row(
+        &mut writer,
+        "Downstream",
+        Stats::of(records.iter().map(|r| r.downstream_bps)),
+        util::format_bps,
+    )?;
+    write_row(
+        &mut writer,
+        "Upstream",
+        Stats::of(records.iter().map(|r| r.upstream_bps)),
+        util::format_bps,
+    )?;
+    write_row(
+        &mut writer,
+        "Latency",
+        Stats::of(records.iter().map(|r| r.latency.as_secs_f64())),
+        |secs| util::format_latency(Duration::from_secs_f64(secs)),
+    )?;
+    Ok(())
+}
+
+fn write_row<W: Write>(
+    writer: &mut W,
+    label: &str,
+    stats: Option<Stats>,
+    format: fn(f64) -> String,
+) -> Result<()> {
+    if let Some(stats) = stats {
+        writeln!(
+            writer,
+            "{:>10}: {:>14} {:>14} {:>14} {:>14}",
+            label,
+            format(stats.min),
+            format(stats.median),
+            format(stats.p95),
+            format(stats.max)
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(values: &[f64]) -> (f64, f64, f64, f64) {
+        let stats = Stats::of(values.iter().copied()).unwrap();
+        (stats.min, stats.median, stats.p95, stats.max)
+    }
+
+    #[test]
+    fn median_and_p95() {
+        assert!(Stats::of(Vec::new()).is_none());
+        assert_eq!(stats(&[7f64]), (7f64, 7f64, 7f64, 7f64));
+        assert_eq!(stats(&[3f64, 1f64, 2f64]), (1f64, 2f64, 3f64, 3f64));
+        // even length takes the mean of the middle values.
+        assert_eq!(stats(&[4f64, 1f64, 3f64, 2f64]), (1f64, 2.5f64, 4f64, 4f64));
+        // nearest rank: the 19th of 20 values.
+        let values: Vec<f64> = (1..=20).rev().map(f64::from).collect();
+        assert_eq!(stats(&values), (1f64, 10.5f64, 19f64, 20f64));
+        let values: Vec<f64> = (1..=21).map(f64::from).collect();
+        assert_eq!(stats(&values).2, 20f64);
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let record = Record {
+            timestamp: DateTime::parse_from_rfc3339("2024-05-01T12:30:45.123456789+00:00")
+                .unwrap()
+                .with_timezone(&Utc),
+            server: "192.0.2.1:5555".to_owned(),
+            downstream_bps: 123_456_789f64,
+            upstream_bps: 98_765f64,
+            latency: Duration::from_micros(12_345),
+        };
+        let line = record.to_csv();
+        assert_eq!(
+            line,
+            "2024-05-01T12:30:45.123456789+00:00,192.0.2.1:5555,123456789,98765,12.345"
+        );
+        let parsed = Record::from_csv(&line).unwrap();
+        assert_eq!(parsed.timestamp, record.timestamp);
+        assert_eq!(parsed.server, record.server);
+        assert_eq!(parsed.downstream_bps, record.downstream_bps);
+        assert_eq!(parsed.upstream_bps, record.upstream_bps);
+        assert_eq!(parsed.latency.as_micros(), record.latency.as_micros());
+        assert_eq!(parsed.to_csv(), line);
+    }
+
+    #[test]
+    fn csv_rejects_malformed_lines() {
+        for line in [
+            "",
+            "2024-05-01T12:30:45+00:00,server,1,2",
+            "2024-05-01T12:30:45+00:00,server,1,2,3,4",
+            "yesterday,server,1,2,3",
+            "2024-05-01T12:30:45+00:00,server,fast,2,3",
+        ] {
+            assert!(Record::from_csv(line).is_err(), "{}", line);
+        }
+    }
+}
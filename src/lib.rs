@@ -1,7 +1,19 @@
+#[cfg(unix)]
+pub mod admin;
 pub mod cli;
 pub mod client;
 pub mod command;
+pub mod config;
+pub mod cpu;
+#[cfg(feature = "tui")]
+pub mod dashboard;
+pub mod discovery;
+pub mod history;
 pub mod logger;
+pub mod monitor;
+pub mod net;
+pub mod payload;
+pub mod progress;
 pub mod server;
 pub mod util;
 
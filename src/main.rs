@@ -1,28 +1,358 @@
-use log::error;
-use netspeed::{cli, logger, Client, Server, DEFAULT_MAX_THREADS};
-use std::env;
+use anyhow::{anyhow, Context};
+use chrono::Utc;
+use clap::ArgMatches;
+use log::{error, warn};
+#[cfg(unix)]
+use netspeed::admin;
+use netspeed::{
+    cli,
+    client::Abort,
+    config::{self, ClientConfig, Config},
+    discovery::{self, DISCOVERY_TIMEOUT},
+    history::{self, History},
+    logger,
+    monitor::Monitor,
+    net::{Connector, Family, SocketOptions},
+    payload::{self, Payload, BLOCK_HEADER_SIZE},
+    progress::Gauge,
+    util, Client, Server, DEFAULT_MAX_THREADS,
+};
+use std::{env, fs, io, path::PathBuf, process, sync::Arc, time::Duration};
 
 fn run() -> Result<(), anyhow::Error> {
     let args = cli::ArgParser::parse(env::args_os());
 
     logger::init(args.occurrences_of("verbose"), args.is_present("server"));
+    util::set_units(args.value_of("units").unwrap().parse().unwrap());
+    let config = Config::load(args.value_of("config"), args.is_present("server"))?;
 
     if let Some(sub) = args.subcommand_matches("server") {
+        if let Some(client) = sub.value_of("connect") {
+            return Server::connect(client);
+        }
+        let server_config = &config.server;
+        let addr = config::resolve(sub, "address", server_config.addr.as_deref()).unwrap();
+        let admin_socket =
+            config::resolve(sub, "admin-socket", server_config.admin_socket.as_deref());
+        match sub.value_of("run") {
+            Some("status") => return server_status(admin_socket, &addr),
+            Some("cancel") => {
+                return server_cancel(admin_socket, &addr, sub.value_of("target").unwrap())
+            }
+            _ => (),
+        }
         let server = Server::new(
-            sub.value_of("address").unwrap(),
-            sub.value_of("max-threads")
+            addr.as_str(),
+            config::resolve(sub, "max-threads", server_config.max_threads)
                 .unwrap()
                 .parse()
                 .unwrap_or(DEFAULT_MAX_THREADS),
         )?;
-        server.run()
+        let server = admin(server, admin_socket, &addr);
+        if config::resolve_flag(sub, "announce", "no-announce", server_config.announce) {
+            server.announce()?.run()
+        } else {
+            server.run()
+        }
+    } else if let Some(sub) = args.subcommand_matches("discover") {
+        let timeout = util::parse_duration(sub.value_of("timeout").unwrap()).unwrap();
+        for server in discovery::discover(timeout)? {
+            println!("{}", server);
+        }
+        Ok(())
+    } else if let Some(sub) = args.subcommand_matches("listen") {
+        Server::listen(sub.value_of("address").unwrap(), sub.is_present("once"))
+    } else if let Some(sub) = args.subcommand_matches("monitor") {
+        let every = util::parse_duration_with_unit(sub.value_of("every").unwrap()).unwrap();
+        let monitor = Monitor::new(every, history(sub)?)
+            .count(sub.value_of("count").map(|n| n.parse().unwrap()));
+        if sub.is_present("tui") {
+            return dashboard(&args, &config.client, every, monitor, history(sub)?);
+        }
+        monitor.run(|| build_client(&args, &config.client)?.measure())
+    } else if let Some(sub) = args.subcommand_matches("history") {
+        let since = match sub.value_of("since") {
+            Some(since) => {
+                let period = util::parse_duration_with_unit(since).unwrap();
+                let since = chrono::Duration::from_std(period)
+                    .ok()
+                    .and_then(|period| Utc::now().checked_sub_signed(period))
+                    .ok_or_else(|| anyhow!("--since {} reaches too far back", since))?;
+                Some(since)
+            }
+            None => None,
+        };
+        let mut records = history(sub)?.read(since)?;
+        if let Some(server) = sub.value_of("server") {
+            records.retain(|record| record.server == server);
+        }
+        history::summarize(&records, io::stdout())
     } else {
-        let client =
-            Client::new(args.value_of("address").unwrap())?.duration(args.value_of("duration"));
-        client.run()
+        let abort = Arc::new(Abort::default());
+        let handler = Arc::clone(&abort);
+        ctrlc::set_handler(move || {
+            if handler.request() {
+                warn!("Aborting the test, press Ctrl-C again to quit immediately");
+            } else {
+                process::exit(130);
+            }
+        })?;
+        build_client(&args, &config.client)?.abort(abort).run()
     }
 }
 
+/// `--admin-socket`, else the default path for the port of the server address.
+#[cfg(unix)]
+fn admin_path(admin_socket: Option<String>, addr: &str) -> PathBuf {
+    admin_socket.map_or_else(
+        || {
+            let port = addr
+                .rsplit(':')
+                .next()
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(5555);
+            admin::default_socket_path(port)
+        },
+        PathBuf::from,
+    )
+}
+
+#[cfg(unix)]
+fn server_status(admin_socket: Option<String>, addr: &str) -> Result<(), anyhow::Error> {
+    admin::status(&admin_path(admin_socket, addr))?.print(io::stdout())
+}
+
+#[cfg(not(unix))]
+fn server_status(_admin_socket: Option<String>, _addr: &str) -> Result<(), anyhow::Error> {
+    Err(anyhow!("The admin socket is only supported on Unix"))
+}
+
+#[cfg(unix)]
+fn server_cancel(
+    admin_socket: Option<String>,
+    addr: &str,
+    target: &str,
+) -> Result<(), anyhow::Error> {
+    for id in admin::cancel(&admin_path(admin_socket, addr), target)? {
+        println!("Cancelled session {}", id);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn server_cancel(
+    _admin_socket: Option<String>,
+    _addr: &str,
+    _target: &str,
+) -> Result<(), anyhow::Error> {
+    Err(anyhow!("The admin socket is only supported on Unix"))
+}
+
+#[cfg(unix)]
+fn admin(server: Server, admin_socket: Option<String>, addr: &str) -> Server {
+    server.admin(&admin_path(admin_socket, addr))
+}
+
+#[cfg(not(unix))]
+fn admin(server: Server, _admin_socket: Option<String>, _addr: &str) -> Server {
+    server
+}
+
+#[cfg(feature = "tui")]
+fn dashboard(
+    args: &ArgMatches,
+    client_config: &ClientConfig,
+    every: Duration,
+    monitor: Monitor,
+    history: History,
+) -> Result<(), anyhow::Error> {
+    use netspeed::dashboard::Dashboard;
+
+    let title = format!(
+        "netspeed monitor, every {:?}, recording to {}",
+        every,
+        history.path().display()
+    );
+    let dashboard = Dashboard::start(title, history.read(None)?)?;
+    // log lines would tear the screen, failures are shown by the dashboard.
+    log::set_max_level(log::LevelFilter::Off);
+    let result = monitor.quiet(true).run(|| {
+        let result = build_client(args, client_config)
+            .and_then(|client| client.observer(dashboard.clone()).measure());
+        dashboard.record(&result);
+        result
+    });
+    dashboard.stop();
+    result
+}
+
+#[cfg(not(feature = "tui"))]
+fn dashboard(
+    _args: &ArgMatches,
+    _client_config: &ClientConfig,
+    _every: Duration,
+    _monitor: Monitor,
+    _history: History,
+) -> Result<(), anyhow::Error> {
+    Err(anyhow!(
+        "Built without dashboard support, enable the `tui` feature"
+    ))
+}
+
+fn build_client(args: &ArgMatches, client_config: &ClientConfig) -> Result<Client, anyhow::Error> {
+    let family = if args.is_present("ipv4") {
+        Family::V4
+    } else if args.is_present("ipv6") {
+        Family::V6
+    } else {
+        Family::Any
+    };
+    let connector = Connector::new()
+        .family(family)
+        .bind(args.value_of("bind").map(|ip| ip.parse().unwrap()))
+        .interface(args.value_of("interface"))
+        .socket_options(SocketOptions {
+            send_buffer: config::resolve_checked(
+                args,
+                "send-buffer",
+                client_config.send_buffer,
+                cli::validate_u32,
+            )?
+            .map(|n| n.parse().unwrap()),
+            recv_buffer: config::resolve_checked(
+                args,
+                "recv-buffer",
+                client_config.recv_buffer,
+                cli::validate_u32,
+            )?
+            .map(|n| n.parse().unwrap()),
+            nodelay: if config::resolve_flag(args, "no-delay", "delay", client_config.no_delay) {
+                Some(true)
+            } else {
+                None
+            },
+            mss: config::resolve_checked(args, "mss", client_config.mss, cli::validate_u32)?
+                .map(|n| n.parse().unwrap()),
+            congestion: config::resolve(args, "congestion", client_config.congestion.as_deref()),
+        });
+    let duration = config::resolve_checked(
+        args,
+        "duration",
+        client_config.duration,
+        cli::validate_duration,
+    )?;
+    let omit = config::resolve_checked(args, "omit", client_config.omit, cli::validate_omit)?;
+    let block_size = config::resolve_checked(
+        args,
+        "block-size",
+        client_config.block_size,
+        cli::validate_block_size,
+    )?;
+    let payload = match (args.value_of("payload-file"), args.value_of("payload")) {
+        (Some(path), _) => Payload::from_file(path)?,
+        (None, Some("random")) => Payload::Random(
+            args.value_of("seed")
+                .map(|seed| seed.parse().unwrap())
+                .unwrap_or_else(payload::random_seed),
+        ),
+        _ => Payload::Zeros,
+    };
+    let verify = args.is_present("verify-payload");
+    if verify
+        && block_size
+            .as_deref()
+            .is_some_and(|n| n.parse::<usize>().unwrap() < BLOCK_HEADER_SIZE)
+    {
+        return Err(anyhow!(
+            "--verify-payload requires a block size of at least {} bytes",
+            BLOCK_HEADER_SIZE
+        ));
+    }
+    let addrs = server_addrs(args, client_config)?;
+    let client = match args.value_of("accept") {
+        Some(listen) => Client::accept(listen, &connector)?,
+        None if addrs.len() == 1 => Client::with_connector(addrs[0].as_str(), &connector)?,
+        None => Client::select(&addrs, &connector)?,
+    }
+    .duration(duration.as_deref())
+    .zerocopy(args.is_present("zerocopy"))
+    .block_size(block_size.as_deref())
+    .omit(omit.as_deref())
+    .payload(payload)
+    .verify(verify)
+    .min_downstream(args.value_of("min-downstream"))
+    .min_upstream(args.value_of("min-upstream"))
+    .max_latency(args.value_of("max-latency"))
+    .format(config::resolve(args, "format", client_config.format).as_deref())
+    .output(args.value_of("output"), args.is_present("append"))
+    .progress(!args.is_present("no-progress") && Gauge::available());
+    Ok(client)
+}
+
+/// History file given with `--history`, or the default location.
+fn history(args: &ArgMatches) -> Result<History, anyhow::Error> {
+    match args.value_of("history") {
+        Some(path) => Ok(History::new(path)),
+        None => History::default_path()
+            .map(History::new)
+            .ok_or_else(|| anyhow!("Could not determine the history location, use --history")),
+    }
+}
+
+/// Servers from `--addr` and `--server-list`, or the config file if neither is given,
+/// with `auto` replaced by the discovered ones.
+fn server_addrs(args: &ArgMatches, config: &ClientConfig) -> Result<Vec<String>, anyhow::Error> {
+    let explicit_addrs = args.occurrences_of("address") > 0;
+    let (mut addrs, server_list): (Vec<String>, _) =
+        if explicit_addrs || args.is_present("server-list") {
+            let addrs = if explicit_addrs {
+                args.values_of("address")
+                    .unwrap()
+                    .map(String::from)
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            (addrs, args.value_of("server-list").map(String::from))
+        } else if config.addr.is_some() || config.server_list.is_some() {
+            (
+                config.addr.iter().cloned().collect(),
+                config.server_list.clone(),
+            )
+        } else {
+            // fall back to the default address.
+            (
+                args.values_of("address")
+                    .unwrap()
+                    .map(String::from)
+                    .collect(),
+                None,
+            )
+        };
+    if let Some(path) = server_list {
+        let list =
+            fs::read_to_string(&path).with_context(|| format!("Read server list {}", path))?;
+        addrs.extend(
+            list.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(String::from),
+        );
+    }
+    if addrs.iter().any(|addr| addr == "auto") {
+        let discovered = discovery::discover(DISCOVERY_TIMEOUT)?;
+        if discovered.is_empty() {
+            return Err(anyhow!("No server found on the local network"));
+        }
+        addrs.retain(|addr| addr != "auto");
+        addrs.extend(discovered.iter().map(|server| server.addr.to_string()));
+    }
+    if addrs.is_empty() {
+        return Err(anyhow!("No server address given"));
+    }
+    Ok(addrs)
+}
+
 fn main() {
     if let Err(err) = run() {
         error!("{:?}", err);
@@ -0,0 +1,84 @@
+use crate::{
+    history::{History, Record},
+    util, Result,
+};
+use log::{info, warn};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// First wait after a failed test, doubled on every further failure up to the interval.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Repeats tests on a schedule and appends the results to the history.
+pub struct Monitor {
+    every: Duration,
+    count: Option<u64>,
+    quiet: bool,
+    history: History,
+}
+
+impl Monitor {
+    pub fn new(every: Duration, history: History) -> Self {
+        Self {
+            every,
+            count: None,
+            quiet: false,
+            history,
+        }
+    }
+
+    /// Stop after `count` tests, failed ones included.
+    pub fn count(mut self, count: Option<u64>) -> Self {
+        self.count = count;
+        self
+    }
+
+    /// Don't print a line per result, e.g. when a dashboard shows them.
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    pub fn run(&self, mut test: impl FnMut() -> Result<Record>) -> Result<()> {
+        info!(
+            "Test every {:?}, recording to {}",
+            self.every,
+            self.history.path().display()
+        );
+        let mut backoff = INITIAL_BACKOFF;
+        let mut completed = 0u64;
+        loop {
+            let start = Instant::now();
+            let wait = match test() {
+                Ok(record) => {
+                    if !self.quiet {
+                        println!(
+                            "{} {} down {} up {} latency {}",
+                            record.timestamp.to_rfc3339(),
+                            record.server,
+                            util::format_bps(record.downstream_bps),
+                            util::format_bps(record.upstream_bps),
+                            util::format_latency(record.latency)
+                        );
+                    }
+                    self.history.append(&record)?;
+                    backoff = INITIAL_BACKOFF;
+                    self.every.saturating_sub(start.elapsed())
+                }
+                Err(err) => {
+                    let wait = backoff.min(self.every);
+                    warn!("Test failed, retry in {:?}: {:#}", wait, err);
+                    backoff = backoff.saturating_mul(2);
+                    wait
+                }
+            };
+            completed += 1;
+            if self.count.is_some_and(|count| completed >= count) {
+                return Ok(());
+            }
+            thread::sleep(wait);
+        }
+    }
+}
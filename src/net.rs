@@ -0,0 +1,494 @@
+use crate::Result;
+use anyhow::{anyhow, Context};
+use log::debug;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Delay before racing the next address while an attempt is still pending (RFC 8305).
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// How often `accept_timeout` polls the listener.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    Any,
+    V4,
+    V6,
+}
+
+impl Family {
+    fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            Family::Any => true,
+            Family::V4 => addr.is_ipv4(),
+            Family::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Family::Any => write!(f, "IP"),
+            Family::V4 => write!(f, "IPv4"),
+            Family::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Connector {
+    family: Family,
+    timeout: Duration,
+    bind: Option<IpAddr>,
+    interface: Option<String>,
+    options: SocketOptions,
+}
+
+impl Default for Connector {
+    fn default() -> Self {
+        Self {
+            family: Family::Any,
+            timeout: CONNECT_TIMEOUT,
+            bind: None,
+            interface: None,
+            options: SocketOptions::default(),
+        }
+    }
+}
+
+impl Connector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn family(mut self, family: Family) -> Self {
+        self.family = family;
+        self
+    }
+
+    /// Use `ip` as the local address of the connection.
+    pub fn bind(mut self, ip: Option<IpAddr>) -> Self {
+        self.bind = ip;
+        self
+    }
+
+    /// Send traffic through the network interface named `interface` (SO_BINDTODEVICE).
+    pub fn interface(mut self, interface: Option<&str>) -> Self {
+        self.interface = interface.map(String::from);
+        self
+    }
+
+    pub fn socket_options(mut self, options: SocketOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    /// Resolve `addr` and connect to the first address that answers.
+    /// Attempts are started one after another, alternating address families,
+    /// and the next one starts as soon as the previous fails or `ATTEMPT_DELAY` passes.
+    pub fn connect(&self, addr: impl ToSocketAddrs + fmt::Debug) -> Result<TcpStream> {
+        let candidates = self.resolve(&addr)?;
+        debug!("Resolved {:?} => {:?}", addr, candidates);
+
+        let (tx, rx) = mpsc::channel();
+        let mut pending = candidates.into_iter();
+        let mut in_flight = 0;
+        let mut errors = Vec::new();
+
+        loop {
+            if let Some(candidate) = pending.next() {
+                let tx = tx.clone();
+                let connector = self.clone();
+                in_flight += 1;
+                thread::spawn(move || {
+                    // receiver is gone once another attempt has won.
+                    let _ = tx.send((candidate, connector.attempt(candidate)));
+                });
+            } else if in_flight == 0 {
+                break;
+            }
+
+            let received = if pending.len() > 0 {
+                rx.recv_timeout(ATTEMPT_DELAY).ok()
+            } else {
+                rx.recv().ok()
+            };
+            if let Some((candidate, result)) = received {
+                in_flight -= 1;
+                match result {
+                    Ok(stream) => return Ok(stream),
+                    Err(err) => {
+                        debug!("Connect to {} failed: {}", candidate, err);
+                        errors.push(format!("{}: {}", candidate, err));
+                    }
+                }
+            }
+        }
+
+        Err(anyhow!(
+            "Could not connect to {:?} ({})",
+            addr,
+            errors.join(", ")
+        ))
+    }
+
+    fn resolve(&self, addr: &(impl ToSocketAddrs + fmt::Debug)) -> Result<Vec<SocketAddr>> {
+        let family = match (self.family, self.bind) {
+            (Family::Any, Some(IpAddr::V4(_))) => Family::V4,
+            (Family::Any, Some(IpAddr::V6(_))) => Family::V6,
+            (family, _) => family,
+        };
+        let addrs: Vec<SocketAddr> = addr
+            .to_socket_addrs()
+            .with_context(|| format!("Resolve {:?}", addr))?
+            .filter(|addr| family.matches(addr))
+            .filter(|addr| self.bind.is_none_or(|ip| ip.is_ipv4() == addr.is_ipv4()))
+            .collect();
+        if addrs.is_empty() {
+            Err(anyhow!("No {} address found for {:?}", family, addr))
+        } else {
+            Ok(interleave(addrs))
+        }
+    }
+
+    fn attempt(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if let Some(interface) = self.interface.as_ref() {
+            bind_device(&socket, interface)?;
+        }
+        self.options.apply(SockRef::from(&socket))?;
+        if let Some(ip) = self.bind {
+            socket.bind(&SocketAddr::new(ip, 0).into())?;
+        }
+        socket.connect_timeout(&addr.into(), self.timeout)?;
+        Ok(socket.into())
+    }
+}
+
+/// Accept a connection on `listener`, giving up once `timeout` passes.
+pub fn accept_timeout(
+    listener: &TcpListener,
+    timeout: Duration,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    let deadline = Instant::now() + timeout;
+    listener.set_nonblocking(true)?;
+    let accepted = loop {
+        match listener.accept() {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    break Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "no connection within timeout",
+                    ));
+                }
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+            accepted => break accepted,
+        }
+    };
+    listener.set_nonblocking(false)?;
+    let (stream, addr) = accepted?;
+    stream.set_nonblocking(false)?;
+    Ok((stream, addr))
+}
+
+/// Socket level tuning requested by the client and applied on both ends.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub send_buffer: Option<u32>,
+    pub recv_buffer: Option<u32>,
+    pub nodelay: Option<bool>,
+    pub mss: Option<u32>,
+    pub congestion: Option<String>,
+}
+
+impl SocketOptions {
+    pub fn is_empty(&self) -> bool {
+        *self == SocketOptions::default()
+    }
+
+    pub fn apply(&self, socket: SockRef<'_>) -> io::Result<()> {
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size as usize)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size as usize)?;
+        }
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay)?;
+        }
+        if let Some(mss) = self.mss {
+            set_mss(&socket, mss)?;
+        }
+        if let Some(congestion) = self.congestion.as_ref() {
+            set_congestion(&socket, congestion)?;
+        }
+        Ok(())
+    }
+
+    /// Read back the values the kernel actually uses for `socket`.
+    pub fn effective(socket: SockRef<'_>) -> Self {
+        Self {
+            send_buffer: socket.send_buffer_size().ok().map(|size| size as u32),
+            recv_buffer: socket.recv_buffer_size().ok().map(|size| size as u32),
+            nodelay: socket.nodelay().ok(),
+            mss: mss(&socket).ok(),
+            congestion: congestion(&socket).ok(),
+        }
+    }
+}
+
+impl fmt::Display for SocketOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut fields = Vec::new();
+        if let Some(size) = self.send_buffer {
+            fields.push(format!("sndbuf={}", size));
+        }
+        if let Some(size) = self.recv_buffer {
+            fields.push(format!("rcvbuf={}", size));
+        }
+        if let Some(nodelay) = self.nodelay {
+            fields.push(format!("nodelay={}", nodelay));
+        }
+        if let Some(mss) = self.mss {
+            fields.push(format!("mss={}", mss));
+        }
+        if let Some(congestion) = self.congestion.as_ref() {
+            fields.push(format!("congestion={}", congestion));
+        }
+        write!(f, "{}", fields.join(" "))
+    }
+}
+
+/// Sender side statistics read from `TCP_INFO`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpStats {
+    pub retransmits: u32,
+    /// Smoothed round trip time in microseconds.
+    pub rtt: u32,
+    /// Round trip time variance in microseconds.
+    pub rtt_var: u32,
+    /// Congestion window in segments.
+    pub cwnd: u32,
+    /// Pacing rate in bytes per second.
+    pub pacing_rate: u64,
+}
+
+impl TcpStats {
+    #[cfg(target_os = "linux")]
+    pub fn read(stream: &TcpStream) -> io::Result<Self> {
+        use std::{mem, os::unix::io::AsRawFd};
+
+        let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        // Safety: `info` is a valid buffer of `len` bytes for the kernel to fill.
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut libc::tcp_info as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            retransmits: info.tcpi_total_retrans,
+            rtt: info.tcpi_rtt,
+            rtt_var: info.tcpi_rttvar,
+            cwnd: info.tcpi_snd_cwnd,
+            pacing_rate: info.tcpi_pacing_rate,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn read(_stream: &TcpStream) -> io::Result<Self> {
+        Err(io::Error::other("TCP_INFO is only supported on Linux"))
+    }
+
+    /// Statistics for the period after `before` was taken.
+    /// Retransmits are cumulative per connection, the other fields are point in time.
+    pub fn since(self, before: Option<&TcpStats>) -> Self {
+        Self {
+            retransmits: self
+                .retransmits
+                .saturating_sub(before.map(|b| b.retransmits).unwrap_or(0)),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for TcpStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "retrans={} rtt={:.2}ms rttvar={:.2}ms cwnd={} pacing={}",
+            self.retransmits,
+            self.rtt as f64 / 1000f64,
+            self.rtt_var as f64 / 1000f64,
+            self.cwnd,
+            crate::util::format_bps(self.pacing_rate.saturating_mul(8) as f64),
+        )
+    }
+}
+
+/// In-memory file holding the payload pattern, whose pages are handed to the socket
+/// with `sendfile(2)` so sending does not copy the payload through user space.
+#[cfg(target_os = "linux")]
+pub struct SendFile {
+    file: std::fs::File,
+    len: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl SendFile {
+    pub fn new(pattern: &[u8]) -> io::Result<Self> {
+        use std::{fs::File, io::Write, os::unix::io::FromRawFd};
+
+        // Safety: the name is a valid nul terminated string.
+        let fd = unsafe { libc::memfd_create(b"netspeed\0".as_ptr() as *const libc::c_char, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: `fd` was just created and is owned by nobody else.
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        if pattern.iter().all(|b| *b == 0) {
+            // sparse file, reads as zeros without touching memory.
+            file.set_len(pattern.len() as u64)?;
+        } else {
+            file.write_all(pattern)?;
+        }
+        Ok(Self {
+            file,
+            len: pattern.len(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Send `range` of the file to `stream`.
+    pub fn send_to(&self, stream: &TcpStream, range: std::ops::Range<usize>) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut offset = range.start as libc::off_t;
+        while (offset as usize) < range.end {
+            let remaining = range.end - offset as usize;
+            // Safety: both descriptors are open for the duration of the call.
+            let n = unsafe {
+                libc::sendfile(
+                    stream.as_raw_fd(),
+                    self.file.as_raw_fd(),
+                    &mut offset,
+                    remaining,
+                )
+            };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            } else if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn mss(socket: &Socket) -> io::Result<u32> {
+    socket.mss()
+}
+
+#[cfg(unix)]
+fn set_mss(socket: &Socket, mss: u32) -> io::Result<()> {
+    socket.set_mss(mss)
+}
+
+#[cfg(not(unix))]
+fn mss(_socket: &Socket) -> io::Result<u32> {
+    Err(io::Error::other("MSS is not supported on this platform"))
+}
+
+#[cfg(not(unix))]
+fn set_mss(_socket: &Socket, _mss: u32) -> io::Result<()> {
+    Err(io::Error::other("MSS is not supported on this platform"))
+}
+
+#[cfg(target_os = "linux")]
+fn congestion(socket: &Socket) -> io::Result<String> {
+    socket.tcp_congestion().map(|name| {
+        String::from_utf8_lossy(&name)
+            .trim_end_matches('\0')
+            .to_owned()
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn set_congestion(socket: &Socket, name: &str) -> io::Result<()> {
+    socket.set_tcp_congestion(name.as_bytes())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn congestion(_socket: &Socket) -> io::Result<String> {
+    Err(io::Error::other(
+        "congestion control is only supported on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_congestion(_socket: &Socket, _name: &str) -> io::Result<()> {
+    Err(io::Error::other(
+        "congestion control is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::other(
+        "binding to an interface is only supported on Linux",
+    ))
+}
+
+/// Reorder addresses so that families alternate, keeping the resolver's preference first.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let prefer_v6 = addrs.first().map(SocketAddr::is_ipv6).unwrap_or(false);
+    let (mut first, mut second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == prefer_v6);
+    first.reverse();
+    second.reverse();
+
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.pop(), second.pop()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use crate::{command::MAX_BLOCK_SIZE, Result};
+use anyhow::{anyhow, Context};
+use std::{
+    collections::hash_map::RandomState,
+    convert::TryInto,
+    fmt, fs,
+    hash::{BuildHasher, Hasher},
+    io,
+    ops::Range,
+    path::Path,
+    sync::Arc,
+};
+
+/// Size of the pre-generated pseudo-random pattern.
+/// Large enough that deduplicating middleboxes do not find repeats within their window.
+/// Allocated by both ends for every direction sent with a random payload.
+const RANDOM_PATTERN_SIZE: usize = 8 * 1024 * 1024;
+
+/// Content of the data frames. The data phase repeats `Payload::pattern` endlessly.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Payload {
+    #[default]
+    Zeros,
+    /// Pseudo-random bytes generated from the seed.
+    Random(u64),
+    /// Contents of a user supplied file.
+    Data(Arc<Vec<u8>>),
+}
+
+impl Payload {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path).with_context(|| format!("Read payload {}", path.display()))?;
+        if data.is_empty() {
+            Err(anyhow!("Payload file {} is empty", path.display()))
+        } else if data.len() > MAX_BLOCK_SIZE {
+            Err(anyhow!(
+                "Payload file {} exceeds {} bytes",
+                path.display(),
+                MAX_BLOCK_SIZE
+            ))
+        } else {
+            Ok(Payload::Data(Arc::new(data)))
+        }
+    }
+
+    /// Bytes repeated over the whole data phase.
+    pub fn pattern(&self, block_size: usize) -> Vec<u8> {
+        match self {
+            Payload::Zeros => vec![0u8; block_size],
+            Payload::Random(seed) => {
+                let mut rng = SplitMix64(*seed);
+                let mut pattern = Vec::with_capacity(RANDOM_PATTERN_SIZE);
+                while pattern.len() < RANDOM_PATTERN_SIZE {
+                    pattern.extend_from_slice(&rng.next_u64().to_le_bytes());
+                }
+                pattern
+            }
+            Payload::Data(data) => data.to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Payload::Zeros => write!(f, "zeros"),
+            Payload::Random(seed) => write!(f, "random(seed={})", seed),
+            Payload::Data(data) => write!(f, "file({} bytes)", data.len()),
+        }
+    }
+}
+
+/// Seed for `Payload::Random` when the user does not pick one.
+pub fn random_seed() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Small, fast and deterministic generator, good enough to defeat compression.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Position in the endless repetition of a pattern of `len` bytes.
+pub struct Cursor {
+    len: usize,
+    offset: usize,
+}
+
+impl Cursor {
+    pub fn new(len: usize) -> Self {
+        Self { len, offset: 0 }
+    }
+
+    /// Split the next `n` bytes of the stream into ranges of the pattern.
+    pub fn advance(
+        &mut self,
+        mut n: usize,
+        mut f: impl FnMut(Range<usize>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        while n > 0 {
+            let take = n.min(self.len - self.offset);
+            f(self.offset..self.offset + take)?;
+            self.offset = (self.offset + take) % self.len;
+            n -= take;
+        }
+        Ok(())
+    }
+}
+
+/// Sequence number and checksum stamped at the head of every block in verify mode.
+/// `[sequence: u64][checksum: u32][data]`, the checksum covers the sequence and the data.
+pub const BLOCK_HEADER_SIZE: usize = 12;
+
+const CHECKSUM_RANGE: Range<usize> = 8..BLOCK_HEADER_SIZE;
+
+/// Word-at-a-time FNV style hash, fast enough not to dominate the transfer.
+#[derive(Clone, Copy)]
+struct Checksum {
+    hash: u64,
+    /// Bytes of an incomplete word carried over to the next `update`.
+    tail: [u8; 8],
+    tail_len: usize,
+}
+
+impl Checksum {
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    fn new() -> Self {
+        Self {
+            hash: 0xcbf2_9ce4_8422_2325,
+            tail: [0u8; 8],
+            tail_len: 0,
+        }
+    }
+
+    fn update(&mut self, mut bytes: &[u8]) {
+        if self.tail_len > 0 {
+            let take = (8 - self.tail_len).min(bytes.len());
+            self.tail[self.tail_len..self.tail_len + take].copy_from_slice(&bytes[..take]);
+            self.tail_len += take;
+            bytes = &bytes[take..];
+            if self.tail_len < 8 {
+                return;
+            }
+            self.mix(u64::from_le_bytes(self.tail));
+            self.tail_len = 0;
+        }
+        let mut words = bytes.chunks_exact(8);
+        for word in words.by_ref() {
+            self.mix(u64::from_le_bytes(word.try_into().unwrap()));
+        }
+        let rest = words.remainder();
+        self.tail[..rest.len()].copy_from_slice(rest);
+        self.tail_len = rest.len();
+    }
+
+    fn mix(&mut self, word: u64) {
+        self.hash = (self.hash ^ word).wrapping_mul(Self::PRIME);
+    }
+
+    fn finish(mut self) -> u32 {
+        if self.tail_len > 0 {
+            let mut word = [0u8; 8];
+            word[..self.tail_len].copy_from_slice(&self.tail[..self.tail_len]);
+            self.mix(u64::from_le_bytes(word) ^ (self.tail_len as u64) << 56);
+        }
+        (self.hash ^ (self.hash >> 32)) as u32
+    }
+}
+
+/// Writes the sequence number and checksum into blocks before they are sent.
+#[derive(Default)]
+pub struct BlockStamper {
+    sequence: u64,
+}
+
+impl BlockStamper {
+    /// `block` must be at least `BLOCK_HEADER_SIZE` long.
+    pub fn stamp(&mut self, block: &mut [u8]) {
+        block[..8].copy_from_slice(&self.sequence.to_be_bytes());
+        let mut checksum = Checksum::new();
+        checksum.update(&block[..CHECKSUM_RANGE.start]);
+        checksum.update(&block[BLOCK_HEADER_SIZE..]);
+        block[CHECKSUM_RANGE].copy_from_slice(&checksum.finish().to_be_bytes());
+        self.sequence += 1;
+    }
+}
+
+/// Result of checking the stamped blocks of one data phase.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Integrity {
+    pub blocks: u64,
+    /// Blocks whose checksum did not match.
+    pub corrupted: u64,
+    /// Sequence numbers that never arrived.
+    pub missing: u64,
+    /// Blocks that arrived after a later sequence number.
+    pub reordered: u64,
+}
+
+impl Integrity {
+    pub fn is_ok(&self) -> bool {
+        self.corrupted == 0 && self.missing == 0 && self.reordered == 0
+    }
+}
+
+impl fmt::Display for Integrity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            write!(f, "ok ({} blocks)", self.blocks)
+        } else {
+            write!(
+                f,
+                "{} corrupted, {} missing, {} reordered of {} blocks",
+                self.corrupted, self.missing, self.reordered, self.blocks
+            )
+        }
+    }
+}
+
+/// Checks sequence numbers and checksums of received blocks.
+pub struct BlockVerifier {
+    header: [u8; BLOCK_HEADER_SIZE],
+    header_filled: usize,
+    checksum: Checksum,
+    expected: u64,
+    integrity: Integrity,
+}
+
+impl Default for BlockVerifier {
+    fn default() -> Self {
+        Self {
+            header: [0u8; BLOCK_HEADER_SIZE],
+            header_filled: 0,
+            checksum: Checksum::new(),
+            expected: 0,
+            integrity: Integrity::default(),
+        }
+    }
+}
+
+impl BlockVerifier {
+    /// Feed payload of the current block, `block_end` tells whether `payload` completes it.
+    pub fn verify(&mut self, mut payload: &[u8], block_end: bool) {
+        if self.header_filled < BLOCK_HEADER_SIZE {
+            let take = (BLOCK_HEADER_SIZE - self.header_filled).min(payload.len());
+            self.header[self.header_filled..self.header_filled + take]
+                .copy_from_slice(&payload[..take]);
+            self.header_filled += take;
+            payload = &payload[take..];
+            if self.header_filled == BLOCK_HEADER_SIZE {
+                self.checksum.update(&self.header[..CHECKSUM_RANGE.start]);
+            }
+        }
+        self.checksum.update(payload);
+        if block_end {
+            self.finish_block();
+        }
+    }
+
+    fn finish_block(&mut self) {
+        let integrity = &mut self.integrity;
+        integrity.blocks += 1;
+        let checksum = std::mem::replace(&mut self.checksum, Checksum::new()).finish();
+        let complete = std::mem::replace(&mut self.header_filled, 0) == BLOCK_HEADER_SIZE;
+        let mut sequence = [0u8; 8];
+        sequence.copy_from_slice(&self.header[..8]);
+        let mut stamped = [0u8; 4];
+        stamped.copy_from_slice(&self.header[CHECKSUM_RANGE]);
+
+        if !complete || checksum != u32::from_be_bytes(stamped) {
+            // the sequence number itself can not be trusted.
+            integrity.corrupted += 1;
+            self.expected += 1;
+            return;
+        }
+        let sequence = u64::from_be_bytes(sequence);
+        if sequence >= self.expected {
+            integrity.missing += sequence - self.expected;
+            self.expected = sequence + 1;
+        } else {
+            integrity.reordered += 1;
+            integrity.missing = integrity.missing.saturating_sub(1);
+        }
+    }
+
+    pub fn integrity(&self) -> Integrity {
+        self.integrity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stamped blocks of `size` bytes with varying content.
+    fn stamped_blocks(count: usize, size: usize) -> Vec<Vec<u8>> {
+        let mut stamper = BlockStamper::default();
+        (0..count)
+            .map(|n| {
+                let mut block: Vec<u8> = (0..size).map(|i| (i * 7 + n) as u8).collect();
+                stamper.stamp(&mut block);
+                block
+            })
+            .collect()
+    }
+
+    /// Feed `blocks` in pieces of `piece` bytes, as reads of the data phase would.
+    fn verify(blocks: &[Vec<u8>], piece: usize) -> Integrity {
+        let mut verifier = BlockVerifier::default();
+        for block in blocks {
+            let mut pieces = block.chunks(piece).peekable();
+            while let Some(bytes) = pieces.next() {
+                verifier.verify(bytes, pieces.peek().is_none());
+            }
+        }
+        verifier.integrity()
+    }
+
+    #[test]
+    fn checksum_carries_partial_words() {
+        let bytes: Vec<u8> = (0..61u8).collect();
+        let mut whole = Checksum::new();
+        whole.update(&bytes);
+        for split in [1, 3, 8, 13] {
+            let mut pieces = Checksum::new();
+            for chunk in bytes.chunks(split) {
+                pieces.update(chunk);
+            }
+            assert_eq!(pieces.finish(), whole.finish(), "split {}", split);
+        }
+    }
+
+    #[test]
+    fn intact_blocks_in_any_split() {
+        let blocks = stamped_blocks(4, 37);
+        for piece in [1, 5, 12, 37] {
+            assert_eq!(
+                verify(&blocks, piece),
+                Integrity {
+                    blocks: 4,
+                    ..Integrity::default()
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn changed_byte_is_corrupted() {
+        let mut blocks = stamped_blocks(3, 64);
+        blocks[1][40] ^= 1;
+        let integrity = verify(&blocks, 7);
+        assert_eq!(integrity.corrupted, 1);
+        assert_eq!(integrity.missing, 0);
+        assert_eq!(integrity.reordered, 0);
+    }
+
+    #[test]
+    fn skipped_sequence_is_missing() {
+        let blocks = stamped_blocks(4, 32);
+        let integrity = verify(
+            &[blocks[0].clone(), blocks[1].clone(), blocks[3].clone()],
+            32,
+        );
+        assert_eq!(
+            integrity,
+            Integrity {
+                blocks: 3,
+                missing: 1,
+                ..Integrity::default()
+            }
+        );
+    }
+
+    #[test]
+    fn late_sequence_is_reordered() {
+        let blocks = stamped_blocks(3, 32);
+        let integrity = verify(
+            &[blocks[0].clone(), blocks[2].clone(), blocks[1].clone()],
+            32,
+        );
+        assert_eq!(
+            integrity,
+            Integrity {
+                blocks: 3,
+                reordered: 1,
+                ..Integrity::default()
+            }
+        );
+    }
+}
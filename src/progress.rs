@@ -0,0 +1,132 @@
+use crate::util;
+use std::{
+    io::{self, IsTerminal, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often observers are told about a running test.
+const TICK: Duration = Duration::from_millis(200);
+const BAR_WIDTH: usize = 30;
+
+/// Snapshot of a running test.
+#[derive(Debug, Clone, Copy)]
+pub struct Tick {
+    /// `Downstream` or `Upstream`.
+    pub label: &'static str,
+    pub elapsed: Duration,
+    /// Omitted warm-up plus test duration.
+    pub total: Duration,
+    /// Throughput over the last tick.
+    pub bps: f64,
+}
+
+/// Receives the progress of running tests.
+pub trait Observer: Send + Sync {
+    fn tick(&self, tick: &Tick);
+
+    /// The test of `label` is over.
+    fn finish(&self, _label: &'static str) {}
+}
+
+/// Gauge line redrawn on stdout.
+pub struct Gauge;
+
+impl Gauge {
+    /// The gauge is only drawn when stdout is a terminal, plain output otherwise.
+    pub fn available() -> bool {
+        io::stdout().is_terminal()
+    }
+}
+
+impl Observer for Gauge {
+    fn tick(&self, tick: &Tick) {
+        let ratio = (tick.elapsed.as_secs_f64() / tick.total.as_secs_f64()).min(1f64);
+        let filled = (ratio * BAR_WIDTH as f64) as usize;
+        let mut stdout = io::stdout().lock();
+        let _ = write!(
+            stdout,
+            "\r{:>10}: [{}{}] {:>5.1}/{} s {:>14}",
+            tick.label,
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            tick.elapsed.as_secs_f64(),
+            tick.total.as_secs(),
+            util::format_bps(tick.bps)
+        );
+        let _ = stdout.flush();
+    }
+
+    fn finish(&self, _label: &'static str) {
+        // erase the gauge so the result starts on a clean line.
+        let mut stdout = io::stdout().lock();
+        let _ = write!(stdout, "\r\x1b[2K");
+        let _ = stdout.flush();
+    }
+}
+
+/// Samples the bytes counted by the data loops and reports them to an observer until dropped.
+pub struct Progress {
+    counter: Arc<AtomicU64>,
+    /// Dropping the sender stops the sampling thread.
+    stop: Option<mpsc::Sender<()>>,
+    sampler: Option<thread::JoinHandle<()>>,
+}
+
+impl Progress {
+    pub fn start(label: &'static str, total: Duration, observer: Arc<dyn Observer>) -> Self {
+        let counter = Arc::new(AtomicU64::new(0));
+        let (stop, stopped) = mpsc::channel();
+        let sampler = {
+            let counter = Arc::clone(&counter);
+            thread::spawn(move || sample(label, total, &counter, stopped, observer.as_ref()))
+        };
+        Self {
+            counter,
+            stop: Some(stop),
+            sampler: Some(sampler),
+        }
+    }
+
+    /// Counter the data loops add transferred bytes to.
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.counter)
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(sampler) = self.sampler.take() {
+            let _ = sampler.join();
+        }
+    }
+}
+
+fn sample(
+    label: &'static str,
+    total: Duration,
+    counter: &AtomicU64,
+    stopped: mpsc::Receiver<()>,
+    observer: &dyn Observer,
+) {
+    let start = Instant::now();
+    let mut last = (start, 0u64);
+    while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(TICK) {
+        let now = Instant::now();
+        let bytes = counter.load(Ordering::Relaxed);
+        let bps = util::to_bps(bytes.saturating_sub(last.1), now - last.0);
+        last = (now, bytes);
+        observer.tick(&Tick {
+            label,
+            elapsed: now - start,
+            total,
+            bps,
+        });
+    }
+    observer.finish(label);
+}
@@ -1,6 +1,11 @@
-use crate::command::DeclineReason;
+#[cfg(unix)]
+use crate::admin::{self, Admin, Status, WorkerStatus};
+use crate::command::{DeclineReason, PROTOCOL_VERSION};
 use crate::{
-    command::{Command, Operator},
+    command::{Command, Operator, Receipt},
+    cpu::CpuTimer,
+    discovery,
+    net::{Connector, SocketOptions},
     util, Result,
 };
 use anyhow::{anyhow, Context};
@@ -8,18 +13,35 @@ use anyhow::{anyhow, Context};
 use byteorder::{ReadBytesExt, WriteBytesExt};
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
+use socket2::Socket;
+#[cfg(unix)]
+use std::path::Path;
 use std::{
-    fmt, io,
-    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io::{self, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
     },
     thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 pub const DEFAULT_MAX_THREADS: u32 = 100;
 
+/// How long a new connection may take to tell whether it is a session or data connection.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long a worker waits for the client to open the data connection of a test.
+const DATA_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the control connection is checked for `Abort` during a downstream test.
+const ABORT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Window over which `server status` measures the current throughput of the sessions.
+#[cfg(unix)]
+const RATE_WINDOW: Duration = Duration::from_millis(500);
+
 pub struct Server {
     listener: TcpListener,
     dispatcher: Arc<Dispatcher>,
@@ -27,24 +49,224 @@ pub struct Server {
 
 impl Server {
     pub fn new(addr: impl ToSocketAddrs + fmt::Debug, max_threads: u32) -> Result<Self> {
-        info!("Listening on {:?} max threads: {}", addr, max_threads);
+        Server::with_dispatcher(addr, Dispatcher::new(max_threads))
+    }
+
+    fn with_dispatcher(
+        addr: impl ToSocketAddrs + fmt::Debug,
+        dispatcher: Dispatcher,
+    ) -> Result<Self> {
+        info!(
+            "Listening on {:?} max threads: {}",
+            addr, dispatcher.max_workers
+        );
         Ok(Server {
             listener: TcpListener::bind(addr).context("Listener binding")?,
-            dispatcher: Arc::new(Dispatcher::new(max_threads)),
+            dispatcher: Arc::new(dispatcher),
         })
     }
 
+    /// Answer `netspeed discover` probes on the local network.
+    pub fn announce(self) -> Result<Self> {
+        discovery::announce(self.listener.local_addr()?.port())?;
+        Ok(self)
+    }
+
+    /// Answer `netspeed server status` on the Unix socket at `path`.
+    /// The server keeps serving tests if the socket is unavailable.
+    #[cfg(unix)]
+    pub fn admin(self, path: &Path) -> Self {
+        if let Err(err) = admin::serve(path, Arc::clone(&self.dispatcher) as Arc<dyn Admin>) {
+            warn!("Admin socket disabled: {:#}", err);
+        }
+        self
+    }
+
     pub fn run(self) -> Result<()> {
         for stream in self.listener.incoming() {
             self.dispatcher.dispatch(stream?)
         }
         Ok(())
     }
+
+    /// Serve sessions one at a time on `addr` and print what this side observed after each one.
+    /// With `once`, return after the first session.
+    pub fn listen(addr: impl ToSocketAddrs + fmt::Debug, once: bool) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let server = Server::with_dispatcher(
+            addr,
+            Dispatcher {
+                reports: Some(tx),
+                ..Dispatcher::new(1)
+            },
+        )?;
+        thread::spawn(move || {
+            if let Err(err) = server.run() {
+                error!("{:#}", err);
+            }
+        });
+        for report in rx {
+            match report {
+                Ok(report) => report.print(io::stdout())?,
+                Err(err) if once => return Err(err),
+                Err(err) => error!("{:#}", err),
+            }
+            if once {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reverse connection: dial a client waiting with `--accept` and serve its session.
+    /// The data connections of the session are dialed back to the same address.
+    pub fn connect(addr: impl ToSocketAddrs + fmt::Debug) -> Result<()> {
+        info!("Connecting to {:?}", addr);
+        let stream = Connector::new().connect(&addr)?;
+        let peer = stream.peer_addr()?;
+        info!("Connected to {}", peer);
+        let (tx, rx) = mpsc::channel();
+        let dispatcher = Arc::new(Dispatcher {
+            dial_back: true,
+            reports: Some(tx),
+            ..Dispatcher::new(1)
+        });
+        dispatcher.handshake(peer, stream)?;
+        // the session ran within the handshake, its outcome is waiting.
+        match rx.try_recv() {
+            Ok(report) => report.map(|_| ()),
+            Err(_) => Ok(()),
+        }
+    }
 }
 
 struct Dispatcher {
     max_workers: u32,
+    /// Workers dial data connections to the client instead of waiting for them.
+    dial_back: bool,
+    /// Receives the report of every finished session.
+    reports: Option<mpsc::Sender<Result<Report>>>,
     active_workers: AtomicUsize,
+    /// Data connections are handed over to the worker owning the session token.
+    sessions: Mutex<HashMap<u64, mpsc::Sender<Operator>>>,
+    started: Instant,
+    totals: Arc<Totals>,
+    /// Active sessions by session id.
+    activities: Mutex<HashMap<u64, Arc<Activity>>>,
+}
+
+/// Counters since the server started.
+#[derive(Default)]
+struct Totals {
+    sessions: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+/// What an active session is doing, listed by `netspeed server status`.
+struct Activity {
+    id: u64,
+    peer: SocketAddr,
+    started: Instant,
+    /// Direction and start of the running test.
+    test: Mutex<Option<(&'static str, Instant)>>,
+    /// Payload bytes moved by the running test.
+    bytes: Arc<AtomicU64>,
+    /// Set by the administrator.
+    cancelled: AtomicBool,
+    /// Stops the data loops, on cancel or when the client aborts.
+    stop: Arc<AtomicBool>,
+    /// Control connection, its read side is shut down to wake an idle worker on cancel.
+    control: Option<Socket>,
+}
+
+impl Activity {
+    fn new(id: u64, peer: SocketAddr, control: Option<Socket>) -> Self {
+        Self {
+            id,
+            peer,
+            started: Instant::now(),
+            test: Mutex::new(None),
+            bytes: Arc::new(AtomicU64::new(0)),
+            cancelled: AtomicBool::new(false),
+            stop: Arc::new(AtomicBool::new(false)),
+            control,
+        }
+    }
+
+    fn matches(&self, target: &str) -> bool {
+        self.id.to_string() == target
+            || self.peer.to_string() == target
+            || self.peer.ip().to_string() == target
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(control) = self.control.as_ref() {
+            if let Err(err) = control.shutdown(Shutdown::Read) {
+                warn!("Shutdown session {}: {}", self.id, err);
+            }
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// A test of `direction` starts, the data loops count into the returned counter.
+    fn begin(&self, direction: &'static str) -> Arc<AtomicU64> {
+        self.bytes.store(0, Ordering::Relaxed);
+        *self.test.lock().unwrap() = Some((direction, Instant::now()));
+        Arc::clone(&self.bytes)
+    }
+
+    /// The test is over, returns the bytes it moved.
+    fn end(&self) -> u64 {
+        self.test.lock().unwrap().take();
+        self.bytes.swap(0, Ordering::Relaxed)
+    }
+
+    #[cfg(unix)]
+    fn sample(&self) -> Sample {
+        Sample {
+            test: self.test.lock().unwrap().map(|(_, start)| start),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            at: Instant::now(),
+        }
+    }
+
+    /// Status with the throughput since `earlier`, a sample of the same test.
+    #[cfg(unix)]
+    fn status(&self, earlier: Sample) -> WorkerStatus {
+        let test = *self.test.lock().unwrap();
+        let now = self.sample();
+        let bps = match (earlier.test, now.test) {
+            (Some(before), Some(after)) if before == after && now.at > earlier.at => {
+                util::to_bps(now.bytes.saturating_sub(earlier.bytes), now.at - earlier.at)
+            }
+            _ => 0f64,
+        };
+        WorkerStatus {
+            id: self.id,
+            peer: self.peer,
+            direction: test.map(|(direction, _)| direction.to_owned()),
+            elapsed: self.started.elapsed(),
+            test_bytes: self.bytes.load(Ordering::Relaxed),
+            test_elapsed: test.map(|(_, start)| start.elapsed()).unwrap_or_default(),
+            bps,
+        }
+    }
+}
+
+/// Byte counter of a session at one point in time.
+#[cfg(unix)]
+#[derive(Clone, Copy)]
+struct Sample {
+    /// Start of the test the bytes belong to.
+    test: Option<Instant>,
+    bytes: u64,
+    at: Instant,
 }
 
 impl Dispatcher {
@@ -52,74 +274,348 @@ impl Dispatcher {
         Self {
             active_workers: AtomicUsize::new(0),
             max_workers: max_threads,
+            dial_back: false,
+            reports: None,
+            sessions: Mutex::new(HashMap::new()),
+            started: Instant::now(),
+            totals: Arc::new(Totals::default()),
+            activities: Mutex::new(HashMap::new()),
         }
     }
 
     fn dispatch(self: &Arc<Self>, stream: TcpStream) {
-        let current_workers = self.active_workers.load(Ordering::Relaxed) as u32;
+        let dispatcher: Arc<Dispatcher> = Arc::clone(self);
+        thread::spawn(move || match stream.peer_addr() {
+            Ok(addr) => {
+                if let Err(err) = dispatcher.handshake(addr, stream) {
+                    error!("{} {:#}", addr, err);
+                }
+            }
+            Err(err) => error!("Could not get peer address: {}", err),
+        });
+    }
+
+    fn handshake(self: &Arc<Self>, addr: SocketAddr, stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut operator = Operator::new(stream);
+        let cmd = operator
+            .read()
+            .context("Read handshake (clients before protocol version 2 are not supported)")?;
+
+        match cmd {
+            Command::Session => {
+                match Dispatcher::check_version(&mut operator) {
+                    Ok(()) => self.dispatch_session(addr, operator),
+                    Err(err) => self.finish_session(addr, Err(err)),
+                }
+                Ok(())
+            }
+            Command::Attach => {
+                let token = operator.read_token()?;
+                operator.socket().set_read_timeout(None)?;
+                let sessions = self.sessions.lock().unwrap();
+                match sessions.get(&token) {
+                    Some(worker) => {
+                        debug!("{} Attach data connection to session {:x}", addr, token);
+                        worker
+                            .send(operator)
+                            .map_err(|_| anyhow!("Session {:x} already closed", token))
+                    }
+                    None => Err(anyhow!("Unknown session {:x}", token)),
+                }
+            }
+            _ => Err(anyhow!("Unexpected handshake command {:?}", cmd)),
+        }
+    }
+
+    fn check_version(operator: &mut Operator) -> Result<()> {
+        let version = operator.read_version()?;
+        if version != PROTOCOL_VERSION {
+            operator.write_decline(DeclineReason::VersionMismatch(PROTOCOL_VERSION), true)?;
+            return Err(anyhow!(
+                "Client speaks protocol version {}, expecting {}",
+                version,
+                PROTOCOL_VERSION
+            ));
+        }
+        operator.socket().set_read_timeout(None)?;
+        Ok(())
+    }
+
+    fn dispatch_session(self: &Arc<Self>, addr: SocketAddr, operator: Operator) {
+        let current_workers = self.active_workers.fetch_add(1, Ordering::Relaxed) as u32;
         if current_workers >= self.max_workers {
+            self.active_workers.fetch_sub(1, Ordering::Relaxed);
             warn!(
                 "Max Threads/Workers counts exceeded. ({}/{})",
                 current_workers, self.max_workers
             );
-            self.decline(stream);
+            self.decline(operator);
+            return;
+        }
+        info!(
+            "Pass concurrent threads check. ({}/{})",
+            current_workers, self.max_workers
+        );
+
+        let token = session_token();
+        let (tx, rx) = mpsc::channel();
+        self.sessions.lock().unwrap().insert(token, tx);
+        let id = self.totals.sessions.fetch_add(1, Ordering::Relaxed) + 1;
+        let activity = Arc::new(Activity::new(id, addr, operator.socket().try_clone().ok()));
+        self.activities
+            .lock()
+            .unwrap()
+            .insert(id, Arc::clone(&activity));
+        let _slot = Slot {
+            dispatcher: self,
+            addr,
+            token,
+            id,
+        };
+        info!(
+            "Handle incoming connection. dispatch worker {} actives: {}",
+            addr,
+            self.active_workers.load(Ordering::SeqCst)
+        );
+        let data = if self.dial_back {
+            DataSource::Dial(addr)
         } else {
-            info!(
-                "Pass concurrent threads check. ({}/{})",
-                current_workers, self.max_workers
-            );
-            self.active_workers.fetch_add(1, Ordering::Relaxed);
-            self.dispatch_worker(stream)
+            DataSource::Attach(rx)
+        };
+        let result = Worker::dispatch(operator, token, data, activity, Arc::clone(&self.totals));
+        self.finish_session(addr, result);
+    }
+
+    /// Hand the outcome of a session to `listen`, or log a failure.
+    fn finish_session(&self, addr: SocketAddr, result: Result<Report>) {
+        let result = result.with_context(|| format!("Session of {}", addr));
+        match self.reports.as_ref() {
+            Some(reports) => {
+                let _ = reports.send(result);
+            }
+            None => {
+                if let Err(err) = result {
+                    error!("{:#}", err);
+                }
+            }
         }
     }
 
-    fn decline(self: &Arc<Self>, stream: TcpStream) {
-        let mut operator = Operator::new(stream);
+    fn decline(self: &Arc<Self>, mut operator: Operator) {
         if let Err(err) =
             operator.write_decline(DeclineReason::MaxThreadsExceed(self.max_workers), false)
         {
             error!("{:#?}", err);
         }
     }
+}
 
-    fn dispatch_worker(self: &Arc<Self>, stream: TcpStream) {
-        let dispatcher: Arc<Dispatcher> = Arc::clone(self);
-        thread::spawn(move || match stream.peer_addr() {
-            Ok(addr) => {
-                info!(
-                    "Handle incoming connection. dispatch worker {} actives: {}",
-                    addr,
-                    dispatcher.active_workers.load(Ordering::SeqCst)
-                );
-                if let Err(err) = Worker::dispatch(addr, stream) {
-                    eprintln!("{:#?}", err);
-                }
-                dispatcher.active_workers.fetch_sub(1, Ordering::Relaxed);
+#[cfg(unix)]
+impl Admin for Dispatcher {
+    fn status(&self) -> Status {
+        let activities: Vec<Arc<Activity>> =
+            self.activities.lock().unwrap().values().cloned().collect();
+        let samples: Vec<Sample> = activities
+            .iter()
+            .map(|activity| activity.sample())
+            .collect();
+        if !activities.is_empty() {
+            thread::sleep(RATE_WINDOW);
+        }
+        let mut workers: Vec<WorkerStatus> = activities
+            .iter()
+            .zip(samples)
+            .map(|(activity, sample)| activity.status(sample))
+            .collect();
+        workers.sort_by_key(|worker| worker.id);
+        Status {
+            uptime: self.started.elapsed(),
+            sessions: self.totals.sessions.load(Ordering::Relaxed),
+            sent: self.totals.sent.load(Ordering::Relaxed),
+            received: self.totals.received.load(Ordering::Relaxed),
+            workers,
+        }
+    }
+
+    fn cancel(&self, target: &str) -> Result<Vec<u64>> {
+        let mut cancelled: Vec<u64> = self
+            .activities
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|activity| activity.matches(target))
+            .map(|activity| {
+                info!("Cancel session {} of {}", activity.id, activity.peer);
+                activity.cancel();
+                activity.id
+            })
+            .collect();
+        if cancelled.is_empty() {
+            return Err(anyhow!("No active session matches {}", target));
+        }
+        cancelled.sort_unstable();
+        Ok(cancelled)
+    }
+}
+
+/// Unpredictable identifier binding data connections to their session.
+fn session_token() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.finish()
+}
+
+/// Worker slot of a session, given back when the worker ends, even by a panic.
+struct Slot<'a> {
+    dispatcher: &'a Dispatcher,
+    addr: SocketAddr,
+    token: u64,
+    id: u64,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let dispatcher = self.dispatcher;
+        dispatcher.sessions.lock().unwrap().remove(&self.token);
+        dispatcher.activities.lock().unwrap().remove(&self.id);
+        dispatcher.active_workers.fetch_sub(1, Ordering::Relaxed);
+        if thread::panicking() {
+            dispatcher.finish_session(self.addr, Err(anyhow!("Worker panicked")));
+        }
+    }
+}
+
+/// What the server side observed during a session, printed by `netspeed listen`.
+struct Report {
+    peer: SocketAddr,
+    /// Bytes written and time spent sending downstream.
+    sent: Option<(u64, Duration)>,
+    /// Upstream as measured on the receiving side.
+    received: Option<Receipt>,
+}
+
+impl Report {
+    fn print<W: Write>(&self, mut writer: W) -> Result<()> {
+        writeln!(writer, "      Peer: {}", self.peer)?;
+        if let Some((bytes, elapsed)) = self.sent {
+            writeln!(
+                writer,
+                "Downstream: {} (sent {} in {:.2} s)",
+                Report::format_bps(bytes, elapsed),
+                util::format_bytes(bytes),
+                elapsed.as_secs_f64()
+            )?;
+        }
+        if let Some(received) = self.received.as_ref() {
+            writeln!(
+                writer,
+                "  Upstream: {} (received {} in {:.2} s)",
+                Report::format_bps(received.bytes, received.elapsed),
+                util::format_bytes(received.bytes),
+                received.elapsed.as_secs_f64()
+            )?;
+            if let Some(integrity) = received.integrity {
+                writeln!(writer, " Integrity: {}", integrity)?;
             }
-            Err(err) => error!("Could not get peer address: {}", err),
-        });
+            if received.omitted_bytes > 0 {
+                writeln!(
+                    writer,
+                    "   Omitted: {} in {:.2} s",
+                    util::format_bytes(received.omitted_bytes),
+                    received.omitted_elapsed.as_secs_f64()
+                )?;
+            }
+        }
+        Ok(())
     }
+
+    fn format_bps(bytes: u64, elapsed: Duration) -> String {
+        if elapsed.is_zero() {
+            util::format_bps(0f64)
+        } else {
+            util::format_bps(util::to_bps(bytes, elapsed))
+        }
+    }
+}
+
+/// Where a worker gets the data connection of each test from.
+enum DataSource {
+    /// Handed over by the dispatcher once the client attached it.
+    Attach(mpsc::Receiver<Operator>),
+    /// Dialed by the worker to the client in reverse connection mode.
+    Dial(SocketAddr),
 }
 
 struct Worker {
     peer: String,
     operator: Operator,
+    token: u64,
+    data: DataSource,
+    options: SocketOptions,
+    report: Report,
+    activity: Arc<Activity>,
+    totals: Arc<Totals>,
 }
 
 impl Worker {
-    fn dispatch(addr: SocketAddr, stream: TcpStream) -> Result<()> {
-        Worker::new(addr, stream).run()
+    fn dispatch(
+        operator: Operator,
+        token: u64,
+        data: DataSource,
+        activity: Arc<Activity>,
+        totals: Arc<Totals>,
+    ) -> Result<Report> {
+        let mut worker = Worker::new(operator, token, data, activity, totals);
+        worker.run()?;
+        Ok(worker.report)
     }
-    fn new(addr: SocketAddr, stream: TcpStream) -> Self {
+    fn new(
+        operator: Operator,
+        token: u64,
+        data: DataSource,
+        activity: Arc<Activity>,
+        totals: Arc<Totals>,
+    ) -> Self {
+        let addr = activity.peer;
         Self {
             peer: format!("{}", addr),
-            operator: Operator::new(stream),
+            operator,
+            token,
+            data,
+            options: SocketOptions::default(),
+            report: Report {
+                peer: addr,
+                sent: None,
+                received: None,
+            },
+            activity,
+            totals,
         }
     }
     fn run(&mut self) -> Result<()> {
+        let result = self.serve();
+        if self.activity.is_cancelled() {
+            info!("{} Cancelled by administrator", self);
+            return self.operator.write_decline(DeclineReason::Cancelled, true);
+        }
+        result
+    }
+
+    fn serve(&mut self) -> Result<()> {
         self.ready().and_then(|_| self.ping_pon())?;
         debug!("{} Successfully ping to client", self);
         loop {
+            if self.activity.is_cancelled() {
+                return Ok(());
+            }
             let cmd = self
                 .operator
                 .read()
@@ -145,6 +641,13 @@ impl Worker {
                     self.handle_upstream()?;
                     info!("{} Successfully handle upstream", self);
                 }
+                Command::SocketOptions => {
+                    self.handle_socket_options()?;
+                }
+                Command::Abort => {
+                    info!("{} Aborted by client", self);
+                    return Ok(());
+                }
                 Command::Close => return Ok(()),
                 _ => return Err(anyhow!("Unexpected command {:?}", cmd)),
             }
@@ -152,31 +655,183 @@ impl Worker {
     }
 
     fn ready(&mut self) -> Result<()> {
-        self.operator.write(Command::Ready)
+        self.operator.write_ready(self.token)
     }
 
     fn ping_pon(&mut self) -> Result<()> {
         self.operator.ping_read_then_write()
     }
 
+    fn handle_socket_options(&mut self) -> Result<()> {
+        // applied to the data connections only, they carry the test.
+        let options = self.operator.read_socket_options()?;
+        debug!("{} Socket options for data connections {}", self, options);
+        self.options = options;
+        Ok(())
+    }
+
+    /// Tell the client what the data socket ended up with, once options were requested.
+    fn report_socket_options(&mut self, data: &Operator) -> Result<()> {
+        if self.options.is_empty() {
+            return Ok(());
+        }
+        let effective = SocketOptions::effective(data.socket());
+        debug!("{} Effective socket options {}", self, effective);
+        self.operator.write_socket_options_report(&effective)
+    }
+
+    fn accept_data(&mut self) -> Result<Operator> {
+        match &self.data {
+            DataSource::Attach(rx) => {
+                let data = rx
+                    .recv_timeout(DATA_CONNECTION_TIMEOUT)
+                    .context("Wait data connection")?;
+                // set after the handshake, so buffer sizes can no longer change the window scale.
+                if let Err(err) = self.options.apply(data.socket()) {
+                    warn!("{} Apply socket options: {}", self, err);
+                }
+                Ok(data)
+            }
+            DataSource::Dial(addr) => self.dial_data(*addr),
+        }
+    }
+
+    fn dial_data(&self, addr: SocketAddr) -> Result<Operator> {
+        let stream = Connector::new()
+            .socket_options(self.options.clone())
+            .connect(addr)
+            .context("Dial data connection")?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut data = Operator::new(stream);
+        data.expect(Command::Attach)?;
+        let token = data.read_token()?;
+        if token != self.token {
+            return Err(anyhow!("Unknown session {:x}", token));
+        }
+        data.socket().set_read_timeout(None)?;
+        Ok(data)
+    }
+
     fn handle_downstream(&mut self) -> Result<()> {
-        let timeout = self.operator.read_duration()?;
-        debug!("{} Timeout: {:?}", self, timeout);
-        let write_bytes = self.operator.write_loop(timeout)?;
+        let transfer = self.operator.read_transfer()?;
+        debug!("{} {:?}", self, transfer);
+        let mut data = self.accept_data()?;
+        let before = data.tcp_stats();
+        let cpu = CpuTimer::start();
+        data.track_progress(self.activity.begin("Downstream"));
+        data.stop_on(Arc::clone(&self.activity.stop));
+        let watch = AbortWatch::start(&self.operator, Arc::clone(&self.activity.stop))?;
+        let start = Instant::now();
+        let result = data.write_loop(&transfer);
+        drop(watch);
+        let sent = self.activity.end();
+        self.totals.sent.fetch_add(sent, Ordering::Relaxed);
+        let write_bytes = result?;
+        if self.activity.is_cancelled() {
+            return Ok(());
+        }
+        self.report.sent = Some((write_bytes, start.elapsed()));
+        let cpu = cpu.utilization();
+        self.report_socket_options(&data)?;
         debug!("{} Write {}", self, util::format_bytes(write_bytes));
-        Ok(())
+        let stats = data.tcp_stats().map(|after| after.since(before.as_ref()));
+        self.operator.write_tcp_stats(stats.as_ref())?;
+        self.operator.write_cpu_usage(cpu)
     }
 
     fn handle_upstream(&mut self) -> Result<()> {
-        // consume
-        let timeout = self.operator.read_duration()?;
-        debug!("{} Timeout: {:?}", self, timeout);
-        let read_bytes = self.operator.read_loop()?;
-        debug!("{} Read {}", self, util::format_bytes(read_bytes));
+        let transfer = self.operator.read_transfer()?;
+        debug!("{} {:?}", self, transfer);
+        let mut data = self.accept_data()?;
+        data.track_progress(self.activity.begin("Upstream"));
+        data.stop_on(Arc::clone(&self.activity.stop));
+        let result = data.read_loop(&transfer);
+        let received = self.activity.end();
+        self.totals.received.fetch_add(received, Ordering::Relaxed);
+        let receipt = result?;
+        if self.activity.is_cancelled() {
+            return Ok(());
+        }
+        debug!(
+            "{} Read {} in {:?} integrity: {:?}",
+            self,
+            util::format_bytes(receipt.bytes),
+            receipt.elapsed,
+            receipt.integrity
+        );
+        self.report_socket_options(&data)?;
+        self.operator.write_receipt(&receipt)?;
+        self.report.received = Some(receipt);
         Ok(())
     }
 }
 
+/// Watches the control connection for `Abort` while the worker is busy sending.
+/// The command is only peeked, the worker reads it after the test.
+struct AbortWatch {
+    control: TcpStream,
+    /// Dropping the sender stops the watching thread.
+    done: Option<mpsc::Sender<()>>,
+    watcher: Option<thread::JoinHandle<()>>,
+}
+
+impl AbortWatch {
+    fn start(operator: &Operator, stop: Arc<AtomicBool>) -> Result<Self> {
+        let control = TcpStream::from(operator.socket().try_clone()?);
+        // a command that arrived along with the request is already buffered, not on the socket.
+        if let Some(&cmd) = operator.buffered().first() {
+            if cmd == u8::from(Command::Abort) {
+                stop.store(true, Ordering::Relaxed);
+            }
+            return Ok(Self {
+                control,
+                done: None,
+                watcher: None,
+            });
+        }
+        control.set_read_timeout(Some(ABORT_POLL_INTERVAL))?;
+        let (done, stopped) = mpsc::channel();
+        let watcher = {
+            let control = control.try_clone()?;
+            thread::spawn(move || {
+                let mut buf = [0u8; 1];
+                while let Err(mpsc::TryRecvError::Empty) = stopped.try_recv() {
+                    match control.peek(&mut buf) {
+                        // the client aborted or went away.
+                        Ok(n) if n == 0 || buf[0] == u8::from(Command::Abort) => {
+                            stop.store(true, Ordering::Relaxed);
+                            break;
+                        }
+                        Ok(_) => break,
+                        Err(err)
+                            if err.kind() == io::ErrorKind::WouldBlock
+                                || err.kind() == io::ErrorKind::TimedOut => {}
+                        Err(_) => break,
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            control,
+            done: Some(done),
+            watcher: Some(watcher),
+        })
+    }
+}
+
+impl Drop for AbortWatch {
+    fn drop(&mut self) {
+        self.done.take();
+        if let Some(watcher) = self.watcher.take() {
+            let _ = watcher.join();
+        }
+        // the timeout is shared with the worker's control connection.
+        if let Err(err) = self.control.set_read_timeout(None) {
+            warn!("Reset control connection timeout: {}", err);
+        }
+    }
+}
+
 impl fmt::Display for Worker {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "(Worker:{}) =>", self.peer.as_str())
@@ -1,29 +1,312 @@
-use std::time::Duration;
+use std::{str::FromStr, sync::OnceLock, time::Duration};
 
 pub fn to_bps(bytes: u64, duration: Duration) -> f64 {
     let bits = bytes * 8;
     bits as f64 / duration.as_secs_f64()
 }
 
-pub fn format_bps(mut bbs: f64) -> String {
-    let units = ["bps", "Kbps", "Mbps", "Gbps", "Tbps"];
-    let mut idx = 0;
-    while bbs > 1024f64 && idx < units.len() {
-        idx += 1;
-        bbs /= 1024f64;
+/// How rates and sizes are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Units {
+    /// Bytes per second instead of bits per second.
+    bytes: bool,
+    /// Powers of 1024 (Mibps, MiB/s) instead of 1000 (Mbps, MB/s).
+    binary: bool,
+    /// Always use this prefix (0 = none, 1 = kilo, ...) instead of the largest fitting one.
+    fixed: Option<usize>,
+}
+
+impl Default for Units {
+    /// SI prefixed bits, like ISPs and iperf.
+    fn default() -> Self {
+        Units {
+            bytes: false,
+            binary: false,
+            fixed: None,
+        }
+    }
+}
+
+impl FromStr for Units {
+    type Err = String;
+
+    /// `si`, `binary`, `bytes`, `binary-bytes`, or a fixed unit such as `mbit`, `mibit`, `mbyte`, `mibyte`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (bytes, binary) = match s {
+            "si" => return Ok(Units::default()),
+            "binary" => (false, true),
+            "bytes" => (true, false),
+            "binary-bytes" => (true, true),
+            _ => {
+                let (prefix, bytes) = if let Some(prefix) = s.strip_suffix("bit") {
+                    (prefix, false)
+                } else if let Some(prefix) = s.strip_suffix("byte") {
+                    (prefix, true)
+                } else {
+                    return Err(format!("Invalid units {:?}", s));
+                };
+                let (prefix, binary) = match prefix.strip_suffix('i') {
+                    Some(prefix) if !prefix.is_empty() => (prefix, true),
+                    _ => (prefix, false),
+                };
+                let fixed = match prefix {
+                    "" if !binary => 0,
+                    "k" => 1,
+                    "m" => 2,
+                    "g" => 3,
+                    "t" => 4,
+                    _ => return Err(format!("Invalid units {:?}", s)),
+                };
+                return Ok(Units {
+                    bytes,
+                    binary,
+                    fixed: Some(fixed),
+                });
+            }
+        };
+        Ok(Units {
+            bytes,
+            binary,
+            fixed: None,
+        })
     }
+}
+
+impl Units {
+    fn base(self) -> f64 {
+        if self.binary {
+            1024f64
+        } else {
+            1000f64
+        }
+    }
+
+    fn prefix(self, exponent: usize) -> &'static str {
+        const SI: [&str; 5] = ["", "K", "M", "G", "T"];
+        const BINARY: [&str; 5] = ["", "Ki", "Mi", "Gi", "Ti"];
+        if self.binary {
+            BINARY[exponent]
+        } else {
+            SI[exponent]
+        }
+    }
+
+    /// Scale `value` to the chosen or the largest fitting prefix.
+    fn scale(self, mut value: f64) -> (f64, usize) {
+        if let Some(exponent) = self.fixed {
+            return (value / self.base().powi(exponent as i32), exponent);
+        }
+        let mut exponent = 0;
+        while value >= self.base() && exponent < 4 {
+            exponent += 1;
+            value /= self.base();
+        }
+        (value, exponent)
+    }
+
+    pub fn format_bps(self, bps: f64) -> String {
+        if self.bytes {
+            let (value, exponent) = self.scale(bps / 8f64);
+            format!("{:.2} {}B/s", value, self.prefix(exponent))
+        } else {
+            let (value, exponent) = self.scale(bps);
+            format!("{:.2} {}bps", value, self.prefix(exponent))
+        }
+    }
+
+    /// Sizes follow the prefix base of the units but always count bytes.
+    pub fn format_bytes(self, bytes: u64) -> String {
+        let units = Units {
+            fixed: None,
+            ..self
+        };
+        let (value, exponent) = units.scale(bytes as f64);
+        if exponent == 0 {
+            format!("{} B", bytes)
+        } else {
+            format!("{:.2} {}B", value, units.prefix(exponent))
+        }
+    }
+}
+
+static UNITS: OnceLock<Units> = OnceLock::new();
 
-    format!("{:.2} {}", bbs, units[idx])
+/// Choose the units of `format_bps` and `format_bytes` for the whole process.
+pub fn set_units(units: Units) {
+    let _ = UNITS.set(units);
+}
+
+fn units() -> Units {
+    UNITS.get().copied().unwrap_or_default()
+}
+
+pub fn format_bps(bps: f64) -> String {
+    units().format_bps(bps)
 }
 
 pub fn format_bytes(bytes: u64) -> String {
-    let mut bytes = bytes as f64;
-    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
-    let mut idx = 0;
-    while bytes >= 1024f64 && idx < units.len() {
-        idx += 1;
-        bytes /= 1024f64;
+    units().format_bytes(bytes)
+}
+
+pub fn format_latency(latency: Duration) -> String {
+    format!("{:.2} ms", latency.as_secs_f64() * 1000f64)
+}
+
+/// Parse a bit rate such as `100M`, `1.5Gbps`, `200Mibps` or `800000`.
+/// `K`, `M`, `G`, `T` are powers of 1000, `Ki`, `Mi`, `Gi`, `Ti` powers of 1024.
+pub fn parse_bps(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let s = s
+        .strip_suffix("bps")
+        .or_else(|| s.strip_suffix("bit"))
+        .unwrap_or(s);
+    let (s, base) = match s.strip_suffix('i') {
+        Some(s) => (s, 1024f64),
+        None => (s, 1000f64),
+    };
+    let (number, scale) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], base),
+        Some('M') | Some('m') => (&s[..s.len() - 1], base.powi(2)),
+        Some('G') | Some('g') => (&s[..s.len() - 1], base.powi(3)),
+        Some('T') | Some('t') => (&s[..s.len() - 1], base.powi(4)),
+        // `i` only marks a binary prefix.
+        _ if base == 1024f64 => return Err(format!("Invalid bit rate {:?}", s)),
+        _ => (s, 1f64),
+    };
+    number
+        .parse::<f64>()
+        .map_err(|err| format!("Invalid bit rate {:?}: {}", s, err))
+        .and_then(|n| {
+            if n.is_sign_negative() || !n.is_finite() {
+                Err(format!("Invalid bit rate {:?}", s))
+            } else {
+                Ok(n * scale)
+            }
+        })
+}
+
+/// Like `parse_duration`, but a number without a unit is an error.
+pub fn parse_duration_with_unit(s: &str) -> Result<Duration, String> {
+    if s.trim().ends_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return Err(format!(
+            "Duration {:?} needs a unit such as s, m, h or d",
+            s
+        ));
     }
+    parse_duration(s)
+}
+
+/// Parse a duration such as `50ms`, `1.5s`, `5m`, `24h`, `7d` or `20` (milliseconds).
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, scale) = if let Some(ms) = s.strip_suffix("ms") {
+        (ms, 0.001f64)
+    } else if let Some(secs) = s.strip_suffix('s') {
+        (secs, 1f64)
+    } else if let Some(mins) = s.strip_suffix('m') {
+        (mins, 60f64)
+    } else if let Some(hours) = s.strip_suffix('h') {
+        (hours, 3600f64)
+    } else if let Some(days) = s.strip_suffix('d') {
+        (days, 86400f64)
+    } else {
+        (s, 0.001f64)
+    };
+    number
+        .parse::<f64>()
+        .map_err(|err| format!("Invalid duration {:?}: {}", s, err))
+        .and_then(|n| {
+            if n.is_sign_negative() || !n.is_finite() {
+                Err(format!("Invalid duration {:?}", s))
+            } else {
+                Ok(Duration::from_secs_f64(n * scale))
+            }
+        })
+}
 
-    format!("{} {}", bytes, units[idx])
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_units() {
+        let accepted = [
+            ("si", false, false, None),
+            ("binary", false, true, None),
+            ("bytes", true, false, None),
+            ("binary-bytes", true, true, None),
+            ("bit", false, false, Some(0)),
+            ("byte", true, false, Some(0)),
+            ("mbit", false, false, Some(2)),
+            ("mibit", false, true, Some(2)),
+            ("gbyte", true, false, Some(3)),
+            ("kibyte", true, true, Some(1)),
+        ];
+        for (s, bytes, binary, fixed) in accepted {
+            assert_eq!(
+                s.parse::<Units>(),
+                Ok(Units {
+                    bytes,
+                    binary,
+                    fixed
+                }),
+                "{}",
+                s
+            );
+        }
+        for s in [
+            "", "ibit", "ibyte", "xbit", "mbits", "MBIT", "mib", "si-bytes",
+        ] {
+            assert!(s.parse::<Units>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn format_with_units() {
+        let cases = [
+            ("si", 999f64, "999.00 bps"),
+            ("si", 1_500_000f64, "1.50 Mbps"),
+            ("si", 2e15, "2000.00 Tbps"),
+            ("binary", 1024f64 * 1024f64, "1.00 Mibps"),
+            ("bytes", 8_000_000f64, "1.00 MB/s"),
+            ("binary-bytes", 8f64 * 1024f64, "1.00 KiB/s"),
+            ("mbit", 1_000f64, "0.00 Mbps"),
+            ("gbit", 2_500_000_000f64, "2.50 Gbps"),
+            ("kibyte", 8f64 * 2048f64, "2.00 KiB/s"),
+            ("bit", 1_500_000f64, "1500000.00 bps"),
+        ];
+        for (units, bps, formatted) in cases {
+            let units: Units = units.parse().unwrap();
+            assert_eq!(units.format_bps(bps), formatted);
+        }
+        let binary: Units = "binary".parse().unwrap();
+        assert_eq!(binary.format_bytes(512), "512 B");
+        assert_eq!(binary.format_bytes(1536), "1.50 KiB");
+        // a fixed unit does not apply to sizes.
+        let mbit: Units = "mbit".parse().unwrap();
+        assert_eq!(mbit.format_bytes(2_000_000_000), "2.00 GB");
+    }
+
+    #[test]
+    fn parse_bit_rates() {
+        let accepted = [
+            ("800000", 800_000f64),
+            ("100M", 100e6),
+            ("100m", 100e6),
+            ("1.5Gbps", 1.5e9),
+            ("2Kbit", 2e3),
+            ("200Mibps", 200f64 * 1024f64 * 1024f64),
+            ("1Ti", 1024f64.powi(4)),
+            (" 5k ", 5e3),
+            ("0", 0f64),
+        ];
+        for (s, bps) in accepted {
+            assert_eq!(parse_bps(s), Ok(bps), "{}", s);
+        }
+        for s in [
+            "", "bps", "5i", "ibps", "M", "5X", "-5M", "nan", "inf", "1e400", "5 M",
+        ] {
+            assert!(parse_bps(s).is_err(), "{}", s);
+        }
+    }
 }